@@ -0,0 +1,241 @@
+/*
+  Copyright 2025 Will Murnane
+
+  Licensed under the Apache License, Version 2.0 (the "License");
+  you may not use this file except in compliance with the License.
+  You may obtain a copy of the License at
+
+      http://www.apache.org/licenses/LICENSE-2.0
+
+  Unless required by applicable law or agreed to in writing, software
+  distributed under the License is distributed on an "AS IS" BASIS,
+  WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+  See the License for the specific language governing permissions and
+  limitations under the License.
+*/
+
+//! `ANALYZE` support for `accessexpression` columns.
+//!
+//! The standard analysis still runs first, so the column gets the usual null fraction, width,
+//! distinct count, most common expressions and histogram. On top of that, the sampled labels are
+//! broken down into the tokens they mention, recorded in the slots core uses for arrays and
+//! `tsvector`:
+//!
+//! - `STATISTIC_KIND_MCELEM`: the most common tokens, with the fraction of non-null rows whose label
+//!   mentions each one, followed by the minimum and maximum of those fractions and a zero (there
+//!   are no null tokens).
+//! - `STATISTIC_KIND_DECHIST`: a histogram of how many distinct tokens each label mentions,
+//!   followed by the average.
+//!
+//! They show up in `pg_stats` as `most_common_elems`, `most_common_elem_freqs` and
+//! `elem_count_histogram`, and are summarized per token by the `access_stats` view.
+
+use crate::storage::StoredExpression;
+use pgrx::prelude::*;
+use pgrx::{FromDatum, Internal, PgMemoryContexts};
+use std::collections::HashMap;
+
+/// What `std_typanalyze` set up, put back in place while its `compute_stats` runs.
+struct StdAnalyze {
+    compute_stats: pg_sys::AnalyzeAttrComputeStatsFunc,
+    extra_data: *mut std::ffi::c_void,
+}
+
+#[pg_extern(strict)]
+fn accessexpression_analyze(mut stats: Internal) -> bool {
+    // SAFETY: ANALYZE calls a type's typanalyze function with the column's VacAttrStats.
+    unsafe {
+        let stats: *mut pg_sys::VacAttrStats = stats
+            .get_mut::<pg_sys::VacAttrStats>()
+            .expect("typanalyze is passed its VacAttrStats");
+        if !pg_sys::std_typanalyze(stats) {
+            return false;
+        }
+        let standard =
+            PgMemoryContexts::For((*stats).anl_context).leak_and_drop_on_delete(StdAnalyze {
+                compute_stats: (*stats).compute_stats,
+                extra_data: (*stats).extra_data,
+            });
+        (*stats).extra_data = standard.cast();
+        (*stats).compute_stats = Some(compute_access_stats);
+        true
+    }
+}
+
+#[pg_guard]
+unsafe extern "C-unwind" fn compute_access_stats(
+    stats: *mut pg_sys::VacAttrStats,
+    fetchfunc: pg_sys::AnalyzeAttrFetchFunc,
+    samplerows: i32,
+    totalrows: f64,
+) {
+    // SAFETY: stats is the VacAttrStats accessexpression_analyze prepared, whose extra_data is our
+    // StdAnalyze, and fetchfunc returns the sampled values of this column.
+    unsafe {
+        let standard = &*(*stats).extra_data.cast::<StdAnalyze>();
+        (*stats).extra_data = standard.extra_data;
+        if let Some(compute_stats) = standard.compute_stats {
+            compute_stats(stats, fetchfunc, samplerows, totalrows);
+        }
+        if !(*stats).stats_valid {
+            return;
+        }
+        let fetch = fetchfunc.expect("ANALYZE passes a fetch function");
+
+        let mut nonnull = 0usize;
+        let mut token_rows: HashMap<String, usize> = HashMap::new();
+        let mut token_counts = Vec::with_capacity(samplerows as usize);
+        for i in 0..samplerows {
+            pgrx::check_for_interrupts!();
+            let mut is_null = false;
+            let value = fetch(stats, i, &mut is_null);
+            let Some(expression) = StoredExpression::from_datum(value, is_null) else {
+                continue;
+            };
+            nonnull += 1;
+            // The token table of a stored expression already lists each token once.
+            for token in expression.tokens() {
+                *token_rows.entry(token.clone()).or_default() += 1;
+            }
+            token_counts.push(expression.tokens().len());
+        }
+        if nonnull == 0 {
+            return;
+        }
+
+        let target = statistics_target(stats).max(1) as usize;
+        let mut common: Vec<(String, usize)> = token_rows.into_iter().collect();
+        common.sort_by(|(a, a_rows), (b, b_rows)| b_rows.cmp(a_rows).then_with(|| a.cmp(b)));
+        common.truncate(target * 10);
+        token_counts.sort_unstable();
+
+        PgMemoryContexts::For((*stats).anl_context).switch_to(|_| {
+            if let Some(slot) = free_slot(stats) {
+                store_common_tokens(stats, slot, &common, nonnull);
+            }
+            if let Some(slot) = free_slot(stats) {
+                store_token_count_histogram(stats, slot, &token_counts, target);
+            }
+        });
+    }
+}
+
+#[cfg(any(feature = "pg17", feature = "pg18"))]
+unsafe fn statistics_target(stats: *mut pg_sys::VacAttrStats) -> i32 {
+    // SAFETY: the caller passes a valid VacAttrStats; its target has the default already applied.
+    unsafe { (*stats).attstattarget }
+}
+
+#[cfg(not(any(feature = "pg17", feature = "pg18")))]
+unsafe fn statistics_target(stats: *mut pg_sys::VacAttrStats) -> i32 {
+    // SAFETY: the caller passes a valid VacAttrStats, whose attr is the column being analyzed.
+    unsafe {
+        match (*(*stats).attr).attstattarget {
+            target if target < 0 => pg_sys::default_statistics_target,
+            target => target,
+        }
+    }
+}
+
+unsafe fn free_slot(stats: *mut pg_sys::VacAttrStats) -> Option<usize> {
+    // SAFETY: the caller passes a valid VacAttrStats.
+    unsafe { (*stats).stakind.iter().position(|&kind| kind == 0) }
+}
+
+/// Copy `numbers` into a palloc'd array in the current memory context.
+unsafe fn palloc_numbers(numbers: &[f32]) -> *mut f32 {
+    // SAFETY: the allocation is sized for every element copied into it.
+    unsafe {
+        let array = pg_sys::palloc(std::mem::size_of_val(numbers)).cast::<f32>();
+        std::ptr::copy_nonoverlapping(numbers.as_ptr(), array, numbers.len());
+        array
+    }
+}
+
+unsafe fn store_common_tokens(
+    stats: *mut pg_sys::VacAttrStats,
+    slot: usize,
+    common: &[(String, usize)],
+    nonnull: usize,
+) {
+    if common.is_empty() {
+        return;
+    }
+    let mut frequencies: Vec<f32> = common
+        .iter()
+        .map(|(_, rows)| *rows as f32 / nonnull as f32)
+        .collect();
+    let min = frequencies.iter().copied().fold(f32::INFINITY, f32::min);
+    let max = frequencies.iter().copied().fold(0.0, f32::max);
+    frequencies.extend([min, max, 0.0]);
+    // SAFETY: stats is valid and slot is unused; everything stored is allocated in the current
+    // (anl_context) memory context, as ANALYZE requires.
+    unsafe {
+        let values = pg_sys::palloc(common.len() * std::mem::size_of::<pg_sys::Datum>())
+            .cast::<pg_sys::Datum>();
+        for (i, (token, _)) in common.iter().enumerate() {
+            *values.add(i) = token.as_str().into_datum().expect("text is never null");
+        }
+        let stats = &mut *stats;
+        stats.stakind[slot] = pg_sys::STATISTIC_KIND_MCELEM as i16;
+        stats.staop[slot] = pg_sys::Oid::from(pg_sys::TextEqualOperator);
+        stats.stacoll[slot] = pg_sys::Oid::from(pg_sys::C_COLLATION_OID);
+        stats.stavalues[slot] = values;
+        stats.numvalues[slot] = common.len() as i32;
+        stats.stanumbers[slot] = palloc_numbers(&frequencies);
+        stats.numnumbers[slot] = frequencies.len() as i32;
+        stats.statypid[slot] = pg_sys::TEXTOID;
+        stats.statyplen[slot] = -1;
+        stats.statypbyval[slot] = false;
+        stats.statypalign[slot] = b'i' as std::ffi::c_char;
+    }
+}
+
+unsafe fn store_token_count_histogram(
+    stats: *mut pg_sys::VacAttrStats,
+    slot: usize,
+    sorted_counts: &[usize],
+    target: usize,
+) {
+    let buckets = target.min(sorted_counts.len()).max(2);
+    let last = sorted_counts.len() - 1;
+    let mut histogram: Vec<f32> = (0..buckets)
+        .map(|i| sorted_counts[i * last / (buckets - 1)] as f32)
+        .collect();
+    let average = sorted_counts.iter().sum::<usize>() as f32 / sorted_counts.len() as f32;
+    histogram.push(average);
+    // SAFETY: stats is valid and slot is unused; the numbers are allocated in the current
+    // (anl_context) memory context, as ANALYZE requires.
+    unsafe {
+        let stats = &mut *stats;
+        stats.stakind[slot] = pg_sys::STATISTIC_KIND_DECHIST as i16;
+        stats.staop[slot] = pg_sys::InvalidOid;
+        stats.stanumbers[slot] = palloc_numbers(&histogram);
+        stats.numnumbers[slot] = histogram.len() as i32;
+    }
+}
+
+extension_sql!(
+    r#"
+CREATE VIEW access_stats AS
+SELECT s.schemaname,
+       s.tablename,
+       s.attname,
+       e.token,
+       e.frequency,
+       round(e.frequency * (1 - s.null_frac) * greatest(c.reltuples, 0)) AS estimated_rows
+FROM pg_stats s
+JOIN pg_namespace n ON n.nspname = s.schemaname
+JOIN pg_class c ON c.relnamespace = n.oid AND c.relname = s.tablename
+JOIN pg_attribute a ON a.attrelid = c.oid AND a.attname = s.attname
+CROSS JOIN LATERAL unnest(s.most_common_elems::text::text[], s.most_common_elem_freqs)
+    AS e(token, frequency)
+WHERE a.atttypid = 'accessexpression'::regtype
+  AND e.token IS NOT NULL;
+
+COMMENT ON VIEW access_stats IS
+    'For each analyzed accessexpression column, the most common tokens and the fraction of labelled rows that mention them';
+"#,
+    name = "access_stats",
+    requires = ["accessexpression"]
+);
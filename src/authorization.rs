@@ -0,0 +1,326 @@
+/*
+  Copyright 2025 Will Murnane
+
+  Licensed under the Apache License, Version 2.0 (the "License");
+  you may not use this file except in compliance with the License.
+  You may obtain a copy of the License at
+
+      http://www.apache.org/licenses/LICENSE-2.0
+
+  Unless required by applicable law or agreed to in writing, software
+  distributed under the License is distributed on an "AS IS" BASIS,
+  WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+  See the License for the specific language governing permissions and
+  limitations under the License.
+*/
+
+//! Per-role maximum authorizations, and the tokens a session has assumed within them.
+//!
+//! As in Accumulo, a superuser records in `access_authorizations` the most a role may ever hold,
+//! and a session then assumes some subset of that with `access_assume_tokens()`. Assumed tokens
+//! live in backend memory rather than in a setting, so `SET` can't change them; they belong to the
+//! role that assumed them, and are not used while some other role is current. They are dropped by
+//! `access_reset_tokens()`, and by `DISCARD ALL` and `RESET ALL`, so a pooled connection handed to
+//! a new client doesn't carry over the last one's tokens.
+//!
+//! Maximums are cached per backend like `access_principals` lookups, and dropped whenever the
+//! table changes or roles are dropped or renamed.
+
+use crate::catalog;
+use crate::storage::StoredTokens;
+use pgrx::pg_sys::panic::ErrorReport;
+use pgrx::prelude::*;
+use pgrx::{function_name, PgLogLevel, PgSqlErrorCode};
+use std::cell::{Cell, RefCell};
+use std::collections::HashMap;
+use std::ffi::c_char;
+
+/// Tokens assumed by a role for the rest of the session.
+struct Assumed {
+    role: pg_sys::Oid,
+    tokens: StoredTokens,
+    /// Who the tokens were vouched for, when they came from a signed assertion.
+    subject: Option<String>,
+}
+
+thread_local! {
+    static ASSUMED: RefCell<Option<Assumed>> = const { RefCell::new(None) };
+    /// Each role's maximum, or `None` for a role without one, as last looked up.
+    static MAXIMUMS: RefCell<HashMap<pg_sys::Oid, Option<StoredTokens>>> =
+        RefCell::new(HashMap::new());
+    /// The `access_authorizations` table's OID, once a lookup has found it.
+    static TABLE: Cell<pg_sys::Oid> = const { Cell::new(pg_sys::InvalidOid) };
+}
+
+static mut PREVIOUS_PROCESS_UTILITY: pg_sys::ProcessUtility_hook_type = None;
+
+pub(crate) fn init() {
+    // SAFETY: called once per backend from _PG_init; the callbacks live as long as the library,
+    // and the previous utility hook is saved so it keeps running after ours.
+    unsafe {
+        pg_sys::CacheRegisterRelcacheCallback(Some(relcache_invalidated), pg_sys::Datum::from(0));
+        pg_sys::CacheRegisterSyscacheCallback(
+            pg_sys::SysCacheIdentifier::AUTHOID as i32,
+            Some(roles_invalidated),
+            pg_sys::Datum::from(0),
+        );
+        PREVIOUS_PROCESS_UTILITY = pg_sys::ProcessUtility_hook;
+        pg_sys::ProcessUtility_hook = Some(process_utility);
+    }
+}
+
+/// Whether `statement` is `DISCARD ALL` or `RESET ALL`.
+///
+/// # Safety
+///
+/// `statement` must be null or point to a valid node.
+unsafe fn resets_session(statement: *mut pg_sys::Node) -> bool {
+    // SAFETY: the node tag says which statement struct the node is.
+    unsafe {
+        match statement.as_ref().map(|node| node.type_) {
+            Some(pg_sys::NodeTag::T_DiscardStmt) => {
+                (*statement.cast::<pg_sys::DiscardStmt>()).target
+                    == pg_sys::DiscardMode::DISCARD_ALL
+            }
+            Some(pg_sys::NodeTag::T_VariableSetStmt) => {
+                (*statement.cast::<pg_sys::VariableSetStmt>()).kind
+                    == pg_sys::VariableSetKind::VAR_RESET_ALL
+            }
+            _ => false,
+        }
+    }
+}
+
+#[cfg(not(feature = "pg13"))]
+#[pg_guard]
+#[allow(clippy::too_many_arguments)]
+unsafe extern "C-unwind" fn process_utility(
+    statement: *mut pg_sys::PlannedStmt,
+    query: *const c_char,
+    read_only_tree: bool,
+    context: pg_sys::ProcessUtilityContext::Type,
+    params: pg_sys::ParamListInfo,
+    environment: *mut pg_sys::QueryEnvironment,
+    destination: *mut pg_sys::DestReceiver,
+    completion: *mut pg_sys::QueryCompletion,
+) {
+    // SAFETY: the arguments are passed through unchanged to the previous hook, or to the standard
+    // implementation if there is none, and the statement is read only after that succeeds.
+    unsafe {
+        match PREVIOUS_PROCESS_UTILITY {
+            Some(previous) => previous(
+                statement,
+                query,
+                read_only_tree,
+                context,
+                params,
+                environment,
+                destination,
+                completion,
+            ),
+            None => pg_sys::standard_ProcessUtility(
+                statement,
+                query,
+                read_only_tree,
+                context,
+                params,
+                environment,
+                destination,
+                completion,
+            ),
+        }
+        if resets_session((*statement).utilityStmt) {
+            reset();
+        }
+    }
+}
+
+#[cfg(feature = "pg13")]
+#[pg_guard]
+unsafe extern "C-unwind" fn process_utility(
+    statement: *mut pg_sys::PlannedStmt,
+    query: *const c_char,
+    context: pg_sys::ProcessUtilityContext::Type,
+    params: pg_sys::ParamListInfo,
+    environment: *mut pg_sys::QueryEnvironment,
+    destination: *mut pg_sys::DestReceiver,
+    completion: *mut pg_sys::QueryCompletion,
+) {
+    // SAFETY: as above.
+    unsafe {
+        match PREVIOUS_PROCESS_UTILITY {
+            Some(previous) => previous(
+                statement,
+                query,
+                context,
+                params,
+                environment,
+                destination,
+                completion,
+            ),
+            None => pg_sys::standard_ProcessUtility(
+                statement,
+                query,
+                context,
+                params,
+                environment,
+                destination,
+                completion,
+            ),
+        }
+        if resets_session((*statement).utilityStmt) {
+            reset();
+        }
+    }
+}
+
+#[pg_guard]
+unsafe extern "C-unwind" fn relcache_invalidated(_arg: pg_sys::Datum, relid: pg_sys::Oid) {
+    // An invalid relid means every relation was invalidated.
+    if relid == pg_sys::InvalidOid || relid == TABLE.get() {
+        MAXIMUMS.with_borrow_mut(HashMap::clear);
+    }
+}
+
+#[pg_guard]
+unsafe extern "C-unwind" fn roles_invalidated(_arg: pg_sys::Datum, _cache: i32, _hash: u32) {
+    MAXIMUMS.with_borrow_mut(HashMap::clear);
+}
+
+/// Run `f` with the tokens the current role has assumed, if it has assumed any.
+pub(crate) fn with_assumed_tokens<R>(f: impl FnOnce(&StoredTokens) -> R) -> Option<R> {
+    let role = catalog::current_role();
+    ASSUMED.with_borrow(|assumed| {
+        assumed
+            .as_ref()
+            .filter(|assumed| assumed.role == role)
+            .map(|assumed| f(&assumed.tokens))
+    })
+}
+
+/// The subject the current role's assumed tokens were vouched for, if any.
+pub(crate) fn assumed_subject() -> Option<String> {
+    let role = catalog::current_role();
+    ASSUMED.with_borrow(|assumed| {
+        assumed
+            .as_ref()
+            .filter(|assumed| assumed.role == role)
+            .and_then(|assumed| assumed.subject.clone())
+    })
+}
+
+/// Make `tokens` the session's tokens while `role` is current.
+pub(crate) fn assume(role: pg_sys::Oid, tokens: StoredTokens, subject: Option<String>) {
+    ASSUMED.set(Some(Assumed {
+        role,
+        tokens,
+        subject,
+    }));
+}
+
+/// Forget any assumed tokens, and the subject they were vouched for.
+fn reset() {
+    ASSUMED.set(None);
+}
+
+/// The most `role` may hold, or `None` if no maximum has been recorded for it.
+pub(crate) fn maximum(role: pg_sys::Oid) -> Option<StoredTokens> {
+    if let Some(maximum) = MAXIMUMS.with_borrow(|cache| cache.get(&role).cloned()) {
+        return maximum;
+    }
+    let query = format!(
+        "SELECT maximum::pg_catalog.text FROM {} WHERE role OPERATOR(pg_catalog.=) $1::pg_catalog.regrole",
+        catalog::qualified("access_authorizations")
+    );
+    let (oid, text) = catalog::as_superuser(|| {
+        let oid = catalog::table_oid("access_authorizations");
+        let text = Spi::get_one_with_args::<String>(&query, &[role.into()]);
+        (oid, text)
+    });
+    if let Some(oid) = oid {
+        TABLE.set(oid);
+    }
+    let maximum = text.ok().flatten().map(|text| {
+        let tokens = ::access::tokens(&text)
+            .unwrap_or_else(|_| panic!("stored tokens \"{text}\" failed to parse"));
+        StoredTokens::from(&crate::AccessTokens(tokens))
+    });
+    MAXIMUMS.with_borrow_mut(|cache| cache.insert(role, maximum.clone()));
+    maximum
+}
+
+/// Assume `tokens` for the rest of the session, within the current role's maximum. Tokens beyond
+/// the maximum are an error, or with `strict => false` a warning, and are not assumed. Returns the
+/// tokens assumed.
+#[pg_extern]
+fn access_assume_tokens(tokens: StoredTokens, strict: default!(bool, true)) -> StoredTokens {
+    let role = catalog::current_role();
+    let Some(maximum) = maximum(role) else {
+        ErrorReport::new(
+            PgSqlErrorCode::ERRCODE_INSUFFICIENT_PRIVILEGE,
+            format!(
+                "role \"{}\" has no maximum access tokens",
+                catalog::role_name(role)
+            ),
+            function_name!(),
+        )
+        .set_hint("A superuser can grant some in access_authorizations.")
+        .report(PgLogLevel::ERROR);
+        unreachable!("ERROR-level reports do not return")
+    };
+    let excess: Vec<&str> = tokens
+        .tokens()
+        .iter()
+        .map(String::as_str)
+        .filter(|token| !maximum.contains(token))
+        .collect();
+    if !excess.is_empty() {
+        ErrorReport::new(
+            PgSqlErrorCode::ERRCODE_INSUFFICIENT_PRIVILEGE,
+            format!(
+                "role \"{}\" may not assume all of the requested access tokens",
+                catalog::role_name(role)
+            ),
+            function_name!(),
+        )
+        .set_detail(format!(
+            "Tokens beyond the role's maximum: {}.",
+            excess.join(", ")
+        ))
+        .report(if strict {
+            PgLogLevel::ERROR
+        } else {
+            PgLogLevel::WARNING
+        });
+    }
+    let assumed = tokens.filter(|token| maximum.contains(token));
+    assume(role, assumed.clone(), None);
+    assumed
+}
+
+/// Forget the tokens assumed with `access_assume_tokens()` or `access_set_signed_tokens()`, so
+/// the session is back to `access.tokens` where that is trusted, and otherwise holds none.
+#[pg_extern]
+fn access_reset_tokens() {
+    reset();
+}
+
+extension_sql!(
+    r#"
+CREATE TABLE access_authorizations (
+    role regrole PRIMARY KEY,
+    maximum accesstokens NOT NULL
+);
+REVOKE ALL ON access_authorizations FROM PUBLIC;
+SELECT pg_catalog.pg_extension_config_dump('access_authorizations', '');
+
+CREATE TRIGGER access_authorizations_changed
+    AFTER INSERT OR UPDATE OR DELETE OR TRUNCATE ON access_authorizations
+    FOR EACH STATEMENT EXECUTE FUNCTION access_catalog_changed();
+
+COMMENT ON TABLE access_authorizations IS
+    'The most access tokens each role may assume with access_assume_tokens()';
+"#,
+    name = "access_authorizations",
+    requires = ["accesstokens", catalog::access_catalog_changed]
+);
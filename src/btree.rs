@@ -0,0 +1,167 @@
+/*
+  Copyright 2025 Will Murnane
+
+  Licensed under the Apache License, Version 2.0 (the "License");
+  you may not use this file except in compliance with the License.
+  You may obtain a copy of the License at
+
+      http://www.apache.org/licenses/LICENSE-2.0
+
+  Unless required by applicable law or agreed to in writing, software
+  distributed under the License is distributed on an "AS IS" BASIS,
+  WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+  See the License for the specific language governing permissions and
+  limitations under the License.
+*/
+
+//! Comparison operators and btree support for `accessexpression` and `accesstokens`.
+//!
+//! These are written out rather than derived with `PostgresEq` and `PostgresOrd`, which would
+//! take the arguments as `::access` values and so parse and canonicalize both sides of every
+//! comparison. Stored values are already canonical, so they are compared as stored: a token set
+//! by its token list, and an expression by its token table and then its program. Equal values
+//! are stored identically, so this order agrees with equality; it is not the order of the text.
+
+use crate::storage::{StoredExpression, StoredTokens};
+use pgrx::prelude::*;
+
+/// Define the six comparison operators and the btree support function for one type, with the
+/// names `PostgresEq` and `PostgresOrd` would give them.
+macro_rules! comparisons {
+    ($ty:ty, $eq:ident, $ne:ident, $lt:ident, $le:ident, $gt:ident, $ge:ident, $cmp:ident) => {
+        #[pg_operator(immutable, parallel_safe)]
+        #[opname(=)]
+        #[negator(<>)]
+        #[commutator(=)]
+        #[restrict(eqsel)]
+        #[join(eqjoinsel)]
+        #[merges]
+        #[hashes]
+        pub(crate) fn $eq(left: $ty, right: $ty) -> bool {
+            left == right
+        }
+
+        #[pg_operator(immutable, parallel_safe)]
+        #[opname(<>)]
+        #[negator(=)]
+        #[commutator(<>)]
+        #[restrict(neqsel)]
+        #[join(neqjoinsel)]
+        fn $ne(left: $ty, right: $ty) -> bool {
+            left != right
+        }
+
+        #[pg_operator(immutable, parallel_safe)]
+        #[opname(<)]
+        #[negator(>=)]
+        #[commutator(>)]
+        #[restrict(scalarltsel)]
+        #[join(scalarltjoinsel)]
+        fn $lt(left: $ty, right: $ty) -> bool {
+            left < right
+        }
+
+        #[pg_operator(immutable, parallel_safe)]
+        #[opname(<=)]
+        #[negator(>)]
+        #[commutator(>=)]
+        #[restrict(scalarlesel)]
+        #[join(scalarlejoinsel)]
+        fn $le(left: $ty, right: $ty) -> bool {
+            left <= right
+        }
+
+        #[pg_operator(immutable, parallel_safe)]
+        #[opname(>)]
+        #[negator(<=)]
+        #[commutator(<)]
+        #[restrict(scalargtsel)]
+        #[join(scalargtjoinsel)]
+        fn $gt(left: $ty, right: $ty) -> bool {
+            left > right
+        }
+
+        #[pg_operator(immutable, parallel_safe)]
+        #[opname(>=)]
+        #[negator(<)]
+        #[commutator(<=)]
+        #[restrict(scalargesel)]
+        #[join(scalargejoinsel)]
+        fn $ge(left: $ty, right: $ty) -> bool {
+            left >= right
+        }
+
+        #[pg_extern(immutable, parallel_safe)]
+        fn $cmp(left: $ty, right: $ty) -> i32 {
+            left.cmp(&right) as i32
+        }
+    };
+}
+
+comparisons!(
+    StoredExpression,
+    accessexpression_eq,
+    accessexpression_ne,
+    accessexpression_lt,
+    accessexpression_le,
+    accessexpression_gt,
+    accessexpression_ge,
+    accessexpression_cmp
+);
+
+comparisons!(
+    StoredTokens,
+    accesstokens_eq,
+    accesstokens_ne,
+    accesstokens_lt,
+    accesstokens_le,
+    accesstokens_gt,
+    accesstokens_ge,
+    accesstokens_cmp
+);
+
+extension_sql!(
+    r#"
+CREATE OPERATOR FAMILY accessexpression_btree_ops USING btree;
+CREATE OPERATOR CLASS accessexpression_btree_ops
+    DEFAULT FOR TYPE accessexpression USING btree FAMILY accessexpression_btree_ops AS
+        OPERATOR 1 <,
+        OPERATOR 2 <=,
+        OPERATOR 3 =,
+        OPERATOR 4 >=,
+        OPERATOR 5 >,
+        FUNCTION 1 accessexpression_cmp(accessexpression, accessexpression);
+"#,
+    name = "accessexpression_btree_ops",
+    requires = [
+        accessexpression_eq,
+        accessexpression_lt,
+        accessexpression_le,
+        accessexpression_gt,
+        accessexpression_ge,
+        accessexpression_cmp
+    ]
+);
+
+extension_sql!(
+    r#"
+CREATE OPERATOR FAMILY accesstokens_btree_ops USING btree;
+CREATE OPERATOR CLASS accesstokens_btree_ops
+    DEFAULT FOR TYPE accesstokens USING btree FAMILY accesstokens_btree_ops AS
+        OPERATOR 1 <,
+        OPERATOR 2 <=,
+        OPERATOR 3 =,
+        OPERATOR 4 >=,
+        OPERATOR 5 >,
+        FUNCTION 1 accesstokens_cmp(accesstokens, accesstokens);
+"#,
+    name = "accesstokens_btree_ops",
+    requires = [
+        accesstokens_eq,
+        accesstokens_lt,
+        accesstokens_le,
+        accesstokens_gt,
+        accesstokens_ge,
+        accesstokens_cmp
+    ]
+);
@@ -0,0 +1,125 @@
+/*
+  Copyright 2025 Will Murnane
+
+  Licensed under the Apache License, Version 2.0 (the "License");
+  you may not use this file except in compliance with the License.
+  You may obtain a copy of the License at
+
+      http://www.apache.org/licenses/LICENSE-2.0
+
+  Unless required by applicable law or agreed to in writing, software
+  distributed under the License is distributed on an "AS IS" BASIS,
+  WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+  See the License for the specific language governing permissions and
+  limitations under the License.
+*/
+
+//! Access to the extension's own tables.
+//!
+//! The tables are revoked from `PUBLIC`, so the functions that consult them on a user's behalf
+//! read them as the bootstrap superuser, the way core's foreign key checks switch to the table
+//! owner.
+//!
+//! Backends cache what they read from the tables. Each table has a statement-level trigger that
+//! sends a relcache invalidation for it whenever it changes, which every backend (including this
+//! one, at the next command) sees through the relcache callback of the module that caches it.
+
+use pgrx::prelude::*;
+use pgrx::{PgTrigger, PgTriggerError};
+use std::ffi::CStr;
+
+/// The extension's name in `pg_extension`.
+const EXTENSION: &str = "access_pgrx";
+
+/// The extension's schema, quoted for use in SQL.
+pub(crate) fn schema() -> String {
+    Spi::get_one_with_args::<String>(
+        "SELECT extnamespace::pg_catalog.regnamespace::pg_catalog.text
+         FROM pg_catalog.pg_extension WHERE extname OPERATOR(pg_catalog.=) $1",
+        &[EXTENSION.into()],
+    )
+    .ok()
+    .flatten()
+    .unwrap_or_else(|| panic!("extension \"{EXTENSION}\" is not installed"))
+}
+
+/// Whether the extension is installed in the current database.
+pub(crate) fn installed() -> bool {
+    Spi::get_one_with_args::<bool>(
+        "SELECT EXISTS (SELECT FROM pg_catalog.pg_extension WHERE extname OPERATOR(pg_catalog.=) $1)",
+        &[EXTENSION.into()],
+    )
+    .ok()
+    .flatten()
+    .unwrap_or(false)
+}
+
+/// The schema-qualified, quoted name of the extension's object `name`.
+pub(crate) fn qualified(name: &str) -> String {
+    format!("{}.{}", schema(), pgrx::spi::quote_identifier(name))
+}
+
+/// Run `f` as the bootstrap superuser, in a security-restricted operation.
+///
+/// If `f` raises an error the previous user is not restored here; aborting the transaction (or
+/// subtransaction) does that.
+pub(crate) fn as_superuser<R>(f: impl FnOnce() -> R) -> R {
+    let mut user = pg_sys::InvalidOid;
+    let mut context = 0;
+    // SAFETY: plain reads and writes of the backend's current user and security context.
+    unsafe {
+        pg_sys::GetUserIdAndSecContext(&mut user, &mut context);
+        pg_sys::SetUserIdAndSecContext(
+            pg_sys::Oid::from(pg_sys::BOOTSTRAP_SUPERUSERID),
+            context
+                | (pg_sys::SECURITY_LOCAL_USERID_CHANGE | pg_sys::SECURITY_RESTRICTED_OPERATION)
+                    as i32,
+        );
+    }
+    let result = f();
+    // SAFETY: as above, restoring what was read.
+    unsafe { pg_sys::SetUserIdAndSecContext(user, context) };
+    result
+}
+
+/// A role, taken from SQL as a `regrole` so callers can name it.
+#[derive(Clone, Copy)]
+pub(crate) struct Regrole(pub(crate) pg_sys::Oid);
+
+/// A table, taken from SQL as a `regclass` so callers can name it.
+#[derive(Clone, Copy)]
+pub(crate) struct Regclass(pub(crate) pg_sys::Oid);
+
+/// The name of the role `role`.
+pub(crate) fn role_name(role: pg_sys::Oid) -> String {
+    // SAFETY: with noerr false, GetUserNameFromId raises an error rather than returning null.
+    unsafe { CStr::from_ptr(pg_sys::GetUserNameFromId(role, false)) }
+        .to_string_lossy()
+        .into_owned()
+}
+
+/// The role whose privileges apply right now (`current_user`).
+pub(crate) fn current_role() -> pg_sys::Oid {
+    // SAFETY: GetUserId only reads backend state.
+    unsafe { pg_sys::GetUserId() }
+}
+
+/// The OID of the extension's table `table`.
+pub(crate) fn table_oid(table: &str) -> Option<pg_sys::Oid> {
+    Spi::get_one_with_args::<pg_sys::Oid>(
+        "SELECT $1::pg_catalog.regclass::pg_catalog.oid",
+        &[qualified(table).as_str().into()],
+    )
+    .ok()
+    .flatten()
+}
+
+/// Tell every backend that the trigger's table has changed.
+#[pg_trigger]
+pub(crate) fn access_catalog_changed<'a>(
+    trigger: &'a PgTrigger<'a>,
+) -> Result<Option<PgHeapTuple<'a, AllocatedByPostgres>>, PgTriggerError> {
+    // SAFETY: the trigger's relation is open, so its OID names a relation.
+    unsafe { pg_sys::CacheInvalidateRelcacheByRelid(trigger.relation()?.oid()) };
+    Ok(None)
+}
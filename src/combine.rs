@@ -0,0 +1,168 @@
+/*
+  Copyright 2025 Will Murnane
+
+  Licensed under the Apache License, Version 2.0 (the "License");
+  you may not use this file except in compliance with the License.
+  You may obtain a copy of the License at
+
+      http://www.apache.org/licenses/LICENSE-2.0
+
+  Unless required by applicable law or agreed to in writing, software
+  distributed under the License is distributed on an "AS IS" BASIS,
+  WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+  See the License for the specific language governing permissions and
+  limitations under the License.
+*/
+
+//! Combining access expressions with `&` and `|`, and simplifying them.
+//!
+//! The operands are joined as trees, not as text, so the result never mixes junctions without
+//! parentheses: an operand that is itself the other kind of junction becomes a parenthesized
+//! group, and one of the same kind is spliced in. The empty expression is always satisfied, so it
+//! drops out of a conjunction and swallows a disjunction.
+//!
+//! Every combination is then simplified, as `access_simplify` does, so labels built up step by
+//! step don't grow with repeated or absorbed clauses. `access_and_agg` and `access_or_agg` fold a
+//! column of labels the same way.
+//!
+//! Simplification only uses idempotence and absorption, so two equivalent expressions can still
+//! simplify differently (`(A|B)&(A|C)` and `A|(B&C)`, say). Equality of `accessexpression`
+//! values stays a comparison of canonical forms, which the btree and hash operator classes rely on.
+
+use crate::storage::StoredExpression;
+use crate::syntax::Expr;
+use crate::AccessExpression;
+use pgrx::prelude::*;
+
+/// The conjunction (`and`) or disjunction of `parts`, where `None` is the empty expression,
+/// simplified.
+pub(crate) fn junction(parts: impl IntoIterator<Item = Option<Expr>>, and: bool) -> Option<Expr> {
+    let mut children: Vec<Expr> = Vec::new();
+    for part in parts {
+        match part {
+            Some(part) => children.push(part),
+            None if and => {}
+            None => return None,
+        }
+    }
+    let tree = match children.len() {
+        0 => return None,
+        1 => children.pop().expect("one part"),
+        _ if and => Expr::And(children),
+        _ => Expr::Or(children),
+    };
+    Some(simplify(tree))
+}
+
+/// Whether `y` implies `x`, judging by their structure alone. Never wrong when it says yes, but
+/// can miss implications that need the distributive law.
+fn implies(y: &Expr, x: &Expr) -> bool {
+    if y == x {
+        return true;
+    }
+    match (y, x) {
+        (_, Expr::And(xs)) => xs.iter().all(|x| implies(y, x)),
+        (Expr::Or(ys), _) => ys.iter().all(|y| implies(y, x)),
+        (_, Expr::Or(xs)) if xs.iter().any(|x| implies(y, x)) => true,
+        (Expr::And(ys), _) => ys.iter().any(|y| implies(y, x)),
+        _ => false,
+    }
+}
+
+/// `expr` with nested junctions of the same kind flattened, and with every clause dropped that
+/// a sibling makes redundant: repeats (`A&A` is `A`), and absorbed clauses (`A&(A|B)` and
+/// `A|(A&B)` are both `A`).
+pub(crate) fn simplify(expr: Expr) -> Expr {
+    let (children, and) = match expr {
+        Expr::Token(_) => return expr,
+        Expr::And(children) => (children, true),
+        Expr::Or(children) => (children, false),
+    };
+    // In a conjunction, a clause implied by a sibling adds nothing; in a disjunction, a clause
+    // implying a sibling does.
+    let redundant = |clause: &Expr, sibling: &Expr| {
+        if and {
+            implies(sibling, clause)
+        } else {
+            implies(clause, sibling)
+        }
+    };
+    let mut kept: Vec<Expr> = Vec::new();
+    for child in children.into_iter().map(simplify) {
+        let spliced = match child {
+            Expr::And(inner) if and => inner,
+            Expr::Or(inner) if !and => inner,
+            other => vec![other],
+        };
+        for clause in spliced {
+            if kept.iter().any(|sibling| redundant(&clause, sibling)) {
+                continue;
+            }
+            kept.retain(|sibling| !redundant(sibling, &clause));
+            kept.push(clause);
+        }
+    }
+    match kept.len() {
+        1 => kept.pop().expect("one clause"),
+        _ if and => Expr::And(kept),
+        _ => Expr::Or(kept),
+    }
+}
+
+/// The canonical expression for `tree`.
+pub(crate) fn expression(tree: Option<&Expr>) -> AccessExpression {
+    AccessExpression::from_tree(tree).unwrap_or_else(|e| {
+        let text = tree.map_or_else(String::new, |tree| tree.to_string());
+        panic!("combined expression \"{text}\" is not valid: {e:?}")
+    })
+}
+
+/// `label` simplified: nested junctions flattened, and repeated or absorbed clauses removed.
+#[pg_extern(immutable, parallel_safe)]
+fn access_simplify(label: StoredExpression) -> AccessExpression {
+    expression(label.tree().map(simplify).as_ref())
+}
+
+/// An expression satisfied exactly when both `a` and `b` are.
+#[pg_operator(immutable, parallel_safe)]
+#[opname(&)]
+#[commutator(&)]
+fn access_and(a: StoredExpression, b: StoredExpression) -> AccessExpression {
+    expression(junction([a.tree(), b.tree()], true).as_ref())
+}
+
+/// An expression satisfied exactly when either `a` or `b` is.
+#[pg_operator(immutable, parallel_safe)]
+#[opname(|)]
+#[commutator(|)]
+fn access_or(a: StoredExpression, b: StoredExpression) -> AccessExpression {
+    expression(junction([a.tree(), b.tree()], false).as_ref())
+}
+
+// The aggregates keep their running label as an `accessexpression`, which is re-canonicalized
+// and simplified at each step. Being an ordinary varlena rather than
+// `internal`, the state needs no serialization to move between parallel workers; the combine
+// function is the transition function itself.
+extension_sql!(
+    r#"
+CREATE AGGREGATE access_and_agg(accessexpression) (
+    SFUNC = access_and,
+    STYPE = accessexpression,
+    COMBINEFUNC = access_and,
+    PARALLEL = SAFE
+);
+COMMENT ON AGGREGATE access_and_agg(accessexpression) IS
+    'The label satisfied exactly when every input label is';
+
+CREATE AGGREGATE access_or_agg(accessexpression) (
+    SFUNC = access_or,
+    STYPE = accessexpression,
+    COMBINEFUNC = access_or,
+    PARALLEL = SAFE
+);
+COMMENT ON AGGREGATE access_or_agg(accessexpression) IS
+    'The label satisfied exactly when any input label is';
+"#,
+    name = "access_expression_aggregates",
+    requires = [access_and, access_or]
+);
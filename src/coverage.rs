@@ -0,0 +1,275 @@
+/*
+  Copyright 2025 Will Murnane
+
+  Licensed under the Apache License, Version 2.0 (the "License");
+  you may not use this file except in compliance with the License.
+  You may obtain a copy of the License at
+
+      http://www.apache.org/licenses/LICENSE-2.0
+
+  Unless required by applicable law or agreed to in writing, software
+  distributed under the License is distributed on an "AS IS" BASIS,
+  WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+  See the License for the specific language governing permissions and
+  limitations under the License.
+*/
+
+//! Finding tables whose access labels nothing enforces.
+//!
+//! A table with an `accessexpression` column is only protected by it if row level security is on
+//! and some policy governing reads evaluates the labels. `access_protection_report()` lists every
+//! such table and whether it is covered, judging by what its `SELECT` and `ALL` policies depend on:
+//! `access_evaluate()`, `access_evaluate_implied()`, or the `<@` and `@>` operators on labels.
+//!
+//! Event triggers run the same check on tables created or altered, and on tables whose policies
+//! are altered or dropped, and the superuser-only `access.unprotected_tables` setting decides what
+//! happens to one that isn't covered: nothing, a warning (the default), an error, or
+//! `access_protect_table()` by its first label column.
+//!
+//! Warnings and errors wait until the transaction commits, so a table can be created and then
+//! protected in the same transaction; by then the table may be covered, or gone. Tables are checked
+//! with the setting in effect when their DDL ran.
+
+use crate::{catalog, protect};
+use pgrx::pg_sys::panic::ErrorReport;
+use pgrx::prelude::*;
+use pgrx::{
+    function_name, GucContext, GucFlags, GucRegistry, GucSetting, PgLogLevel, PgSqlErrorCode,
+    PostgresGucEnum,
+};
+use std::cell::RefCell;
+use std::ffi::c_void;
+
+/// What to do about a new or altered table with uncovered labels.
+#[derive(Clone, Copy, PartialEq, Eq, PostgresGucEnum)]
+enum UnprotectedTables {
+    #[name = c"off"]
+    Off,
+    #[name = c"warn"]
+    Warn,
+    #[name = c"error"]
+    Error,
+    #[name = c"protect"]
+    Protect,
+}
+
+static UNPROTECTED_TABLES: GucSetting<UnprotectedTables> =
+    GucSetting::<UnprotectedTables>::new(UnprotectedTables::Warn);
+
+thread_local! {
+    /// Tables to check when the transaction commits, with the setting their DDL ran under.
+    static PENDING: RefCell<Vec<(pg_sys::Oid, UnprotectedTables)>> =
+        const { RefCell::new(Vec::new()) };
+}
+
+pub(crate) fn init() {
+    // SAFETY: called once per backend from _PG_init; the callback lives as long as the library.
+    unsafe { pg_sys::RegisterXactCallback(Some(transaction_ending), std::ptr::null_mut()) };
+    GucRegistry::define_enum_guc(
+        c"access.unprotected_tables",
+        c"What to do when DDL leaves a table's access labels unenforced.",
+        c"One of off, warn, error, or protect, which calls access_protect_table() with the table's first accessexpression column.",
+        &UNPROTECTED_TABLES,
+        GucContext::Suset,
+        GucFlags::default(),
+    );
+}
+
+#[pg_guard]
+unsafe extern "C-unwind" fn transaction_ending(event: pg_sys::XactEvent::Type, _arg: *mut c_void) {
+    match event {
+        pg_sys::XactEvent::XACT_EVENT_PRE_COMMIT | pg_sys::XactEvent::XACT_EVENT_PRE_PREPARE => {
+            check_pending_tables()
+        }
+        pg_sys::XactEvent::XACT_EVENT_ABORT | pg_sys::XactEvent::XACT_EVENT_PARALLEL_ABORT => {
+            PENDING.with_borrow_mut(Vec::clear)
+        }
+        _ => {}
+    }
+}
+
+/// The name and label columns of `relation`, if it has access label columns that no policy
+/// covers.
+fn uncovered(relation: pg_sys::Oid) -> Option<(String, Vec<String>)> {
+    Spi::get_two_with_args::<String, Vec<String>>(
+        &format!(
+            "SELECT relation::pg_catalog.text, label_columns::pg_catalog.text[] FROM {}()
+             WHERE relation::pg_catalog.oid OPERATOR(pg_catalog.=) $1 AND NOT covered",
+            catalog::qualified("access_protection_report")
+        ),
+        &[relation.into()],
+    )
+    .ok()
+    .and_then(|(table, columns)| table.zip(columns))
+}
+
+/// Warn or raise an error about each table queued by DDL in this transaction that is still
+/// uncovered. Run as the transaction commits.
+pub(crate) fn check_pending_tables() {
+    let pending = PENDING.take();
+    if pending.is_empty() {
+        return;
+    }
+    // SAFETY: the transaction is still open; an error leaves the snapshot for abort to pop.
+    unsafe { pg_sys::PushActiveSnapshot(pg_sys::GetTransactionSnapshot()) };
+    // The extension may have been dropped since, taking the tables' label columns with it.
+    let pending = if catalog::installed() {
+        pending
+    } else {
+        Vec::new()
+    };
+    for (relation, setting) in pending {
+        let Some((table, columns)) = uncovered(relation) else {
+            continue;
+        };
+        let level = match setting {
+            UnprotectedTables::Error => PgLogLevel::ERROR,
+            _ => PgLogLevel::WARNING,
+        };
+        ErrorReport::new(
+            PgSqlErrorCode::ERRCODE_OBJECT_NOT_IN_PREREQUISITE_STATE,
+            format!("table {table} has access labels that no row level security policy enforces"),
+            function_name!(),
+        )
+        .set_detail(format!("Label columns: {}.", columns.join(", ")))
+        .set_hint(format!(
+            "Protect it with access_protect_table('{table}', '{}').",
+            columns
+                .first()
+                .expect("a reported table has a label column")
+        ))
+        .report(level);
+    }
+    // SAFETY: pops the snapshot pushed above.
+    unsafe { pg_sys::PopActiveSnapshot() };
+}
+
+/// Act on `access.unprotected_tables` if `relation` has access label columns that no policy
+/// covers: protect it now, or queue it to be checked at commit. Called by the
+/// `access_unprotected_tables` event triggers.
+#[pg_extern]
+pub(crate) fn access_check_table_protection(relation: pg_sys::Oid) {
+    if protect::protecting() {
+        return;
+    }
+    let setting = UNPROTECTED_TABLES.get();
+    match setting {
+        UnprotectedTables::Off => {}
+        UnprotectedTables::Warn | UnprotectedTables::Error => PENDING.with_borrow_mut(|pending| {
+            pending.retain(|(queued, _)| *queued != relation);
+            pending.push((relation, setting));
+        }),
+        UnprotectedTables::Protect => {
+            let Some((table, columns)) = uncovered(relation) else {
+                return;
+            };
+            let column = columns
+                .first()
+                .expect("a reported table has a label column");
+            protect::access_protect_table(catalog::Regclass(relation), column, false, false, None);
+            pgrx::notice!("protected table {table} by its access label column \"{column}\"");
+        }
+    }
+}
+
+extension_sql!(
+    r#"
+CREATE FUNCTION access_protection_report()
+RETURNS TABLE (
+    relation regclass,
+    label_columns name[],
+    row_security boolean,
+    forced boolean,
+    covered boolean
+)
+LANGUAGE sql STABLE
+SET search_path = pg_catalog, @extschema@
+AS $$
+    WITH members AS (
+        SELECT classid, objid FROM pg_depend
+        WHERE refclassid = 'pg_extension'::regclass AND deptype = 'e'
+          AND refobjid = (SELECT oid FROM pg_extension WHERE extname = 'access_pgrx')
+    ), evaluators AS (
+        SELECT 'pg_proc'::regclass AS classid, p.oid AS objid
+        FROM pg_proc p JOIN members m ON m.classid = 'pg_proc'::regclass AND m.objid = p.oid
+        WHERE p.proname IN ('access_evaluate', 'access_evaluate_implied')
+        UNION ALL
+        SELECT 'pg_operator'::regclass, o.oid
+        FROM pg_operator o JOIN members m ON m.classid = 'pg_operator'::regclass AND m.objid = o.oid
+        WHERE o.oprname IN ('<@', '@>') AND 'accessexpression'::regtype IN (o.oprleft, o.oprright)
+    )
+    SELECT c.oid::regclass,
+           array_agg(a.attname ORDER BY a.attnum),
+           c.relrowsecurity,
+           c.relforcerowsecurity,
+           c.relrowsecurity AND EXISTS (
+               SELECT FROM pg_policy p
+               JOIN pg_depend d ON d.classid = 'pg_policy'::regclass AND d.objid = p.oid
+               JOIN evaluators e ON e.classid = d.refclassid AND e.objid = d.refobjid
+               WHERE p.polrelid = c.oid AND p.polcmd IN ('r', '*')
+           )
+    FROM pg_class c
+    JOIN pg_attribute a ON a.attrelid = c.oid
+    WHERE c.relkind IN ('r', 'p')
+      AND a.atttypid = 'accessexpression'::regtype
+      AND a.attnum > 0 AND NOT a.attisdropped
+    GROUP BY c.oid
+    ORDER BY c.oid::regclass::text
+$$;
+
+COMMENT ON FUNCTION access_protection_report() IS
+    'Every table with an accessexpression column, and whether a row level security policy evaluates its labels';
+
+CREATE FUNCTION access_unprotected_tables()
+RETURNS event_trigger
+LANGUAGE plpgsql
+SET search_path = pg_catalog, @extschema@
+AS $$
+DECLARE
+    relation oid;
+BEGIN
+    FOR relation IN
+        SELECT objid FROM pg_event_trigger_ddl_commands() WHERE object_type = 'table'
+        UNION
+        SELECT p.polrelid FROM pg_event_trigger_ddl_commands() c
+        JOIN pg_policy p ON p.oid = c.objid
+        WHERE c.object_type = 'policy'
+    LOOP
+        PERFORM access_check_table_protection(relation);
+    END LOOP;
+END
+$$;
+
+CREATE EVENT TRIGGER access_unprotected_tables ON ddl_command_end
+    WHEN TAG IN ('CREATE TABLE', 'CREATE TABLE AS', 'SELECT INTO', 'ALTER TABLE', 'ALTER POLICY')
+    EXECUTE FUNCTION access_unprotected_tables();
+
+-- A dropped policy is gone by the time sql_drop fires, so its table is found by name: a policy's
+-- address_names are its table's schema and name, then its own name. Policies dropped along with
+-- their table name a table that no longer exists.
+CREATE FUNCTION access_unprotected_tables_dropped()
+RETURNS event_trigger
+LANGUAGE plpgsql
+SET search_path = pg_catalog, @extschema@
+AS $$
+DECLARE
+    relation regclass;
+BEGIN
+    FOR relation IN
+        SELECT DISTINCT to_regclass(format('%I.%I', address_names[1], address_names[2]))
+        FROM pg_event_trigger_dropped_objects() WHERE object_type = 'policy'
+    LOOP
+        IF relation IS NOT NULL THEN
+            PERFORM access_check_table_protection(relation::oid);
+        END IF;
+    END LOOP;
+END
+$$;
+
+CREATE EVENT TRIGGER access_unprotected_tables_dropped ON sql_drop
+    WHEN TAG IN ('DROP POLICY')
+    EXECUTE FUNCTION access_unprotected_tables_dropped();
+"#,
+    name = "access_unprotected_tables",
+    requires = ["accessexpression", access_check_table_protection]
+);
@@ -0,0 +1,142 @@
+/*
+  Copyright 2025 Will Murnane
+
+  Licensed under the Apache License, Version 2.0 (the "License");
+  you may not use this file except in compliance with the License.
+  You may obtain a copy of the License at
+
+      http://www.apache.org/licenses/LICENSE-2.0
+
+  Unless required by applicable law or agreed to in writing, software
+  distributed under the License is distributed on an "AS IS" BASIS,
+  WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+  See the License for the specific language governing permissions and
+  limitations under the License.
+*/
+
+//! Datum conversions for the access types.
+//!
+//! These are the impls `#[derive(PostgresType)]` used to generate for us. The types are declared
+//! by hand (see `io.rs`), so the conversions are too. The stored bytes are described in
+//! `storage.rs`; besides the two public types, the lighter [`StoredExpression`] and
+//! [`StoredTokens`] views can be used as arguments wherever a function only needs to evaluate.
+//!
+//! The OID alias types the extension's functions take, like [`Regrole`], get conversions here
+//! too: pgrx maps `pg_sys::Oid` to plain `oid`.
+
+use crate::catalog::{Regclass, Regrole};
+use crate::storage::{Stored, StoredExpression, StoredTokens};
+use crate::{AccessExpression, AccessTokens};
+use pgrx::callconv::{Arg, ArgAbi, BoxRet, FcInfo};
+use pgrx::datum::Datum;
+use pgrx::pgrx_sql_entity_graph::metadata::{
+    ArgumentError, Returns, ReturnsError, SqlMapping, SqlTranslatable,
+};
+use pgrx::{pg_sys, FromDatum, IntoDatum};
+
+macro_rules! access_datum {
+    ($ty:ident, $sql_name:literal) => {
+        impl FromDatum for $ty {
+            unsafe fn from_polymorphic_datum(
+                datum: pg_sys::Datum,
+                is_null: bool,
+                _typoid: pg_sys::Oid,
+            ) -> Option<Self> {
+                if is_null {
+                    None
+                } else {
+                    // SAFETY: a non-null datum of this type is one of our varlenas.
+                    Some(unsafe { <$ty as Stored>::from_stored(datum) })
+                }
+            }
+        }
+
+        impl IntoDatum for $ty {
+            fn into_datum(self) -> Option<pg_sys::Datum> {
+                self.encode().into_datum()
+            }
+
+            fn type_oid() -> pg_sys::Oid {
+                pgrx::wrappers::regtypein($sql_name)
+            }
+        }
+
+        unsafe impl SqlTranslatable for $ty {
+            fn argument_sql() -> Result<SqlMapping, ArgumentError> {
+                Ok(SqlMapping::As(String::from($sql_name)))
+            }
+
+            fn return_sql() -> Result<Returns, ReturnsError> {
+                Ok(Returns::One(SqlMapping::As(String::from($sql_name))))
+            }
+        }
+
+        unsafe impl<'fcx> ArgAbi<'fcx> for $ty {
+            unsafe fn unbox_arg_unchecked(arg: Arg<'_, 'fcx>) -> Self {
+                let index = arg.index();
+                unsafe { arg.unbox_arg_using_from_datum() }
+                    .unwrap_or_else(|| panic!("argument {index} must not be null"))
+            }
+        }
+
+        unsafe impl BoxRet for $ty {
+            unsafe fn box_into<'fcx>(self, fcinfo: &mut FcInfo<'fcx>) -> Datum<'fcx> {
+                match self.into_datum() {
+                    Some(datum) => unsafe { fcinfo.return_raw_datum(datum) },
+                    None => fcinfo.return_null(),
+                }
+            }
+        }
+    };
+}
+
+access_datum!(AccessExpression, "accessexpression");
+access_datum!(AccessTokens, "accesstokens");
+access_datum!(StoredExpression, "accessexpression");
+access_datum!(StoredTokens, "accesstokens");
+
+macro_rules! oid_alias_datum {
+    ($ty:ident, $sql_name:literal, $type_oid:expr) => {
+        impl FromDatum for $ty {
+            unsafe fn from_polymorphic_datum(
+                datum: pg_sys::Datum,
+                is_null: bool,
+                typoid: pg_sys::Oid,
+            ) -> Option<Self> {
+                // SAFETY: an OID alias datum is an OID.
+                unsafe { pg_sys::Oid::from_polymorphic_datum(datum, is_null, typoid) }.map($ty)
+            }
+        }
+
+        impl IntoDatum for $ty {
+            fn into_datum(self) -> Option<pg_sys::Datum> {
+                self.0.into_datum()
+            }
+
+            fn type_oid() -> pg_sys::Oid {
+                $type_oid
+            }
+        }
+
+        unsafe impl SqlTranslatable for $ty {
+            fn argument_sql() -> Result<SqlMapping, ArgumentError> {
+                Ok(SqlMapping::As(String::from($sql_name)))
+            }
+
+            fn return_sql() -> Result<Returns, ReturnsError> {
+                Ok(Returns::One(SqlMapping::As(String::from($sql_name))))
+            }
+        }
+
+        unsafe impl<'fcx> ArgAbi<'fcx> for $ty {
+            unsafe fn unbox_arg_unchecked(arg: Arg<'_, 'fcx>) -> Self {
+                let index = arg.index();
+                unsafe { arg.unbox_arg_using_from_datum() }
+                    .unwrap_or_else(|| panic!("argument {index} must not be null"))
+            }
+        }
+    };
+}
+
+oid_alias_datum!(Regclass, "regclass", pg_sys::REGCLASSOID);
+oid_alias_datum!(Regrole, "regrole", pg_sys::REGROLEOID);
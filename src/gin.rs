@@ -0,0 +1,151 @@
+/*
+  Copyright 2025 Will Murnane
+
+  Licensed under the Apache License, Version 2.0 (the "License");
+  you may not use this file except in compliance with the License.
+  You may obtain a copy of the License at
+
+      http://www.apache.org/licenses/LICENSE-2.0
+
+  Unless required by applicable law or agreed to in writing, software
+  distributed under the License is distributed on an "AS IS" BASIS,
+  WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+  See the License for the specific language governing permissions and
+  limitations under the License.
+*/
+
+//! The `<@` operator ("expression is satisfied by tokens") and a GIN operator class for it.
+//!
+//! The index stores every distinct token each expression mentions. A non-empty expression can only
+//! be satisfied if at least one of its tokens is held, so a search for `label <@ tokens` looks up
+//! the held tokens (plus the empty expression, which everyone satisfies) and rechecks each
+//! candidate row against the full expression. Rows whose labels mention none of the held tokens
+//! are never visited.
+//!
+//! The operators' restriction estimators are in `planner.rs`, and evaluate the tokens against the
+//! label column's statistics as `access_evaluate`'s support function does.
+
+use crate::planner;
+use crate::storage::{StoredExpression, StoredTokens};
+use pgrx::prelude::*;
+use pgrx::Internal;
+
+/// Whether `tokens` satisfy `expression`; the same test as `access_evaluate`.
+#[pg_operator(immutable, parallel_safe)]
+#[opname(<@)]
+#[commutator(@>)]
+#[join(contjoinsel)]
+fn accessexpression_satisfied_by(expression: StoredExpression, tokens: StoredTokens) -> bool {
+    expression.evaluate(&tokens)
+}
+
+/// Whether `tokens` satisfy `expression`, with the arguments the other way around.
+#[pg_operator(immutable, parallel_safe)]
+#[opname(@>)]
+#[commutator(<@)]
+#[join(contjoinsel)]
+fn accesstokens_satisfies(tokens: StoredTokens, expression: StoredExpression) -> bool {
+    expression.evaluate(&tokens)
+}
+
+/// Copy `keys` into a palloc'd array of text datums, storing its length in `*count`.
+fn key_array(keys: &[String], mut count: Internal) -> Internal {
+    // SAFETY: GIN passes an int32 out-parameter for the number of keys.
+    unsafe { *count.get_mut::<i32>().expect("GIN passes a key count") = keys.len() as i32 };
+    if keys.is_empty() {
+        return Internal::from(None);
+    }
+    // SAFETY: the array is sized for keys.len() datums and every slot is written below.
+    unsafe {
+        let array = pg_sys::palloc(keys.len() * std::mem::size_of::<pg_sys::Datum>())
+            .cast::<pg_sys::Datum>();
+        for (i, key) in keys.iter().enumerate() {
+            *array.add(i) = key.as_str().into_datum().expect("text is never null");
+        }
+        Internal::from(Some(pg_sys::Datum::from(array)))
+    }
+}
+
+#[pg_extern(immutable, parallel_safe, strict)]
+fn access_gin_extract_value(expression: StoredExpression, nentries: Internal) -> Internal {
+    key_array(expression.tokens(), nentries)
+}
+
+#[pg_extern(immutable, parallel_safe, strict)]
+fn access_gin_extract_query(
+    query: StoredTokens,
+    nkeys: Internal,
+    _strategy: i16,
+    _pmatch: Internal,
+    _extra_data: Internal,
+    _null_flags: Internal,
+    mut search_mode: Internal,
+) -> Internal {
+    // SAFETY: GIN passes an int32 out-parameter for the search mode. Including empty items finds
+    // the rows labelled with the empty expression, which every token set satisfies.
+    unsafe {
+        *search_mode
+            .get_mut::<i32>()
+            .expect("GIN passes a search mode") = pg_sys::GIN_SEARCH_MODE_INCLUDE_EMPTY as i32
+    };
+    key_array(query.tokens(), nkeys)
+}
+
+#[pg_extern(immutable, parallel_safe, strict)]
+fn access_gin_consistent(
+    _check: Internal,
+    _strategy: i16,
+    _query: StoredTokens,
+    _nkeys: i32,
+    _extra_data: Internal,
+    mut recheck: Internal,
+    _query_keys: Internal,
+    _null_flags: Internal,
+) -> bool {
+    // The index only knows which held tokens an expression mentions, not how they combine, so
+    // every candidate (one sharing a token with the query, or the empty expression) is rechecked.
+    // SAFETY: GIN passes a bool out-parameter for the recheck flag.
+    unsafe {
+        *recheck
+            .get_mut::<bool>()
+            .expect("GIN passes a recheck flag") = true
+    };
+    true
+}
+
+extension_sql!(
+    r#"
+CREATE OPERATOR CLASS accessexpression_gin_ops
+    DEFAULT FOR TYPE accessexpression USING gin AS
+        OPERATOR 1 <@ (accessexpression, accesstokens),
+        FUNCTION 1 bttextcmp(text, text),
+        FUNCTION 2 access_gin_extract_value(accessexpression, internal),
+        FUNCTION 3 access_gin_extract_query(accesstokens, internal, int2, internal, internal, internal, internal),
+        FUNCTION 4 access_gin_consistent(internal, int2, accesstokens, int4, internal, internal, internal, internal),
+        STORAGE text;
+"#,
+    name = "accessexpression_gin_ops",
+    requires = [
+        accessexpression_satisfied_by,
+        access_gin_extract_value,
+        access_gin_extract_query,
+        access_gin_consistent
+    ]
+);
+
+// Set separately so the estimators are sure to exist when the operators are given them.
+extension_sql!(
+    r#"
+ALTER OPERATOR <@ (accessexpression, accesstokens)
+    SET (RESTRICT = accessexpression_satisfied_by_sel);
+ALTER OPERATOR @> (accesstokens, accessexpression)
+    SET (RESTRICT = accesstokens_satisfies_sel);
+"#,
+    name = "accessexpression_satisfied_by_estimators",
+    requires = [
+        accessexpression_satisfied_by,
+        accesstokens_satisfies,
+        planner::accessexpression_satisfied_by_sel,
+        planner::accesstokens_satisfies_sel
+    ]
+);
@@ -0,0 +1,167 @@
+/*
+  Copyright 2025 Will Murnane
+
+  Licensed under the Apache License, Version 2.0 (the "License");
+  you may not use this file except in compliance with the License.
+  You may obtain a copy of the License at
+
+      http://www.apache.org/licenses/LICENSE-2.0
+
+  Unless required by applicable law or agreed to in writing, software
+  distributed under the License is distributed on an "AS IS" BASIS,
+  WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+  See the License for the specific language governing permissions and
+  limitations under the License.
+*/
+
+//! The `access.tokens` setting and the functions that read it.
+//!
+//! The setting is registered by hand rather than through pgrx's `GucRegistry`, which has no way
+//! to attach a check hook: this one parses the new value with `::access::tokens`, so a bad token
+//! list is rejected by `SET` itself instead of by whichever query next reads it. The parsed
+//! tokens are cached per backend and only re-parsed when the setting's text changes.
+//!
+//! Anyone can `SET access.tokens`, so it is only as trustworthy as the application connecting.
+//! Tokens assumed with `access_assume_tokens()` take precedence over it, and a role with a maximum
+//! in `access_authorizations` holds only what it has assumed: the setting is ignored for it. Any
+//! other role grants itself whatever tokens it sets, unless a superuser turns
+//! `access.trust_tokens_setting` off or configures `access.signing_key`, either of which makes the
+//! setting ignored altogether.
+//!
+//! Assumed tokens live in the leader's memory, so the functions here are parallel restricted.
+
+use crate::storage::{StoredExpression, StoredTokens};
+use crate::{authorization, catalog, planner, signed, syntax, AccessTokens};
+use pgrx::prelude::*;
+use pgrx::{GucContext, GucFlags, GucRegistry, GucSetting};
+use std::cell::RefCell;
+use std::ffi::{c_char, c_void, CStr, CString};
+
+static mut TOKENS: *mut c_char = std::ptr::null_mut();
+static TRUST_TOKENS_SETTING: GucSetting<bool> = GucSetting::<bool>::new(true);
+
+thread_local! {
+    /// The setting text the cached tokens were parsed from, and the tokens.
+    static CURRENT: RefCell<Option<(CString, StoredTokens)>> = const { RefCell::new(None) };
+}
+
+pub(crate) fn init() {
+    // SAFETY: called once from _PG_init; the strings are static and TOKENS lives forever.
+    unsafe {
+        pg_sys::DefineCustomStringVariable(
+            c"access.tokens".as_ptr(),
+            c"Access tokens held by the current session.".as_ptr(),
+            c"A comma-separated accesstokens value, read by access_current_tokens() and the one-argument access_evaluate().".as_ptr(),
+            std::ptr::addr_of_mut!(TOKENS),
+            c"".as_ptr(),
+            pg_sys::GucContext::PGC_USERSET,
+            0,
+            Some(check_tokens),
+            None,
+            None,
+        );
+    }
+    GucRegistry::define_bool_guc(
+        c"access.trust_tokens_setting",
+        c"Whether access.tokens is taken as the session's tokens.",
+        c"When off, a session holds only the tokens it assumed with access_assume_tokens().",
+        &TRUST_TOKENS_SETTING,
+        GucContext::Suset,
+        GucFlags::default(),
+    );
+}
+
+#[pg_guard]
+unsafe extern "C-unwind" fn check_tokens(
+    newval: *mut *mut c_char,
+    _extra: *mut *mut c_void,
+    _source: pg_sys::GucSource::Type,
+) -> bool {
+    // SAFETY: the GUC machinery passes the proposed value, which is null or a C string.
+    let value = unsafe { (*newval).as_ref().map(|value| CStr::from_ptr(value)) };
+    let Some(value) = value else {
+        return true;
+    };
+    let text = match value.to_str() {
+        Ok(text) => text,
+        Err(_) => return reject("The value is not valid UTF-8."),
+    };
+    match ::access::tokens(text) {
+        Ok(_) => true,
+        Err(e) => match syntax::parse_tokens(text) {
+            Err(located) => reject(&format!(
+                "{} at character {}.",
+                located.message,
+                located.offset + 1
+            )),
+            Ok(_) => reject(&format!("{e:?}")),
+        },
+    }
+}
+
+/// Fail a check hook with `detail`, the way `GUC_check_errdetail()` does.
+fn reject(detail: &str) -> bool {
+    let detail = CString::new(detail).unwrap_or_default();
+    // SAFETY: the GUC machinery reads (and then resets) this string once the hook returns.
+    unsafe {
+        pg_sys::GUC_check_errcode(PgSqlErrorCode::ERRCODE_INVALID_PARAMETER_VALUE as i32);
+        pg_sys::GUC_check_errdetail_string = pg_sys::pstrdup(detail.as_ptr());
+    }
+    false
+}
+
+/// Run `f` with the current session's tokens: those assumed by the current role if there are any,
+/// otherwise those in `access.tokens` if it is trusted, no signing key is configured and the role
+/// has no maximum, otherwise none.
+pub(crate) fn with_current_tokens<R>(f: impl Fn(&StoredTokens) -> R) -> R {
+    if let Some(result) = authorization::with_assumed_tokens(&f) {
+        return result;
+    }
+    if !TRUST_TOKENS_SETTING.get()
+        || signed::signing_configured()
+        || authorization::maximum(catalog::current_role()).is_some()
+    {
+        return f(&StoredTokens::default());
+    }
+    with_setting_tokens(f)
+}
+
+/// Run `f` with the tokens currently in `access.tokens`, parsing them only if the setting has
+/// changed since the last call.
+fn with_setting_tokens<R>(f: impl FnOnce(&StoredTokens) -> R) -> R {
+    // SAFETY: TOKENS is either null or the setting's current value, maintained by the GUC code.
+    let setting = unsafe { TOKENS.as_ref().map(|value| CStr::from_ptr(value)) }.unwrap_or(c"");
+    CURRENT.with_borrow_mut(|current| {
+        if !current
+            .as_ref()
+            .is_some_and(|(text, _)| text.as_c_str() == setting)
+        {
+            let text = setting.to_str().expect("checked when the setting was made");
+            let tokens = ::access::tokens(text).expect("checked when the setting was made");
+            *current = Some((
+                setting.to_owned(),
+                StoredTokens::from(&AccessTokens(tokens)),
+            ));
+        }
+        let (_, tokens) = current.as_ref().expect("cache filled above");
+        f(tokens)
+    })
+}
+
+/// The tokens held by the current session: assumed with `access_assume_tokens()`, or from the
+/// `access.tokens` setting.
+#[pg_extern(stable, parallel_restricted)]
+fn access_current_tokens() -> StoredTokens {
+    with_current_tokens(StoredTokens::clone)
+}
+
+/// Whether the current session's tokens (see `access_current_tokens()`) satisfy `expression`.
+#[pg_extern(
+    stable,
+    parallel_restricted,
+    name = "access_evaluate",
+    support = planner::access_evaluate_support
+)]
+fn access_evaluate_current(expression: StoredExpression) -> bool {
+    with_current_tokens(|tokens| expression.evaluate(tokens))
+}
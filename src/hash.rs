@@ -0,0 +1,88 @@
+/*
+  Copyright 2025 Will Murnane
+
+  Licensed under the Apache License, Version 2.0 (the "License");
+  you may not use this file except in compliance with the License.
+  You may obtain a copy of the License at
+
+      http://www.apache.org/licenses/LICENSE-2.0
+
+  Unless required by applicable law or agreed to in writing, software
+  distributed under the License is distributed on an "AS IS" BASIS,
+  WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+  See the License for the specific language governing permissions and
+  limitations under the License.
+*/
+
+//! Hash support for `accessexpression` and `accesstokens`.
+//!
+//! Values are hashed through their canonical stored encoding with PostgreSQL's own `hash_bytes`
+//! family, rather than pgrx's `PostgresHash` derive, so that the 32-bit and seeded 64-bit support
+//! functions agree (the low 32 bits of the extended hash with seed 0 equal the plain hash). Equal
+//! values, such as `'b|a'` and `'a|b'`, are stored identically and so always hash alike; values
+//! written in the legacy format are re-encoded first, without parsing any label text.
+
+use crate::storage::{Stored, StoredExpression, StoredTokens};
+use pgrx::prelude::*;
+
+fn hash_stored(bytes: &[u8]) -> i32 {
+    // SAFETY: hash_bytes only reads `len` bytes from the pointer.
+    unsafe { pg_sys::hash_bytes(bytes.as_ptr(), bytes.len() as i32) as i32 }
+}
+
+fn hash_stored_extended(bytes: &[u8], seed: i64) -> i64 {
+    // SAFETY: hash_bytes_extended only reads `len` bytes from the pointer.
+    unsafe { pg_sys::hash_bytes_extended(bytes.as_ptr(), bytes.len() as i32, seed as u64) as i64 }
+}
+
+#[pg_extern(immutable, parallel_safe, strict)]
+fn accessexpression_hash(value: StoredExpression) -> i32 {
+    hash_stored(&value.encode())
+}
+
+#[pg_extern(immutable, parallel_safe, strict)]
+fn accessexpression_hash_extended(value: StoredExpression, seed: i64) -> i64 {
+    hash_stored_extended(&value.encode(), seed)
+}
+
+#[pg_extern(immutable, parallel_safe, strict)]
+fn accesstokens_hash(value: StoredTokens) -> i32 {
+    hash_stored(&value.encode())
+}
+
+#[pg_extern(immutable, parallel_safe, strict)]
+fn accesstokens_hash_extended(value: StoredTokens, seed: i64) -> i64 {
+    hash_stored_extended(&value.encode(), seed)
+}
+
+extension_sql!(
+    r#"
+CREATE OPERATOR CLASS accessexpression_hash_ops
+    DEFAULT FOR TYPE accessexpression USING hash AS
+        OPERATOR 1 = (accessexpression, accessexpression),
+        FUNCTION 1 accessexpression_hash(accessexpression),
+        FUNCTION 2 accessexpression_hash_extended(accessexpression, int8);
+"#,
+    name = "accessexpression_hash_ops",
+    requires = [
+        crate::btree::accessexpression_eq,
+        accessexpression_hash,
+        accessexpression_hash_extended
+    ]
+);
+
+extension_sql!(
+    r#"
+CREATE OPERATOR CLASS accesstokens_hash_ops
+    DEFAULT FOR TYPE accesstokens USING hash AS
+        OPERATOR 1 = (accesstokens, accesstokens),
+        FUNCTION 1 accesstokens_hash(accesstokens),
+        FUNCTION 2 accesstokens_hash_extended(accesstokens, int8);
+"#,
+    name = "accesstokens_hash_ops",
+    requires = [
+        crate::btree::accesstokens_eq,
+        accesstokens_hash,
+        accesstokens_hash_extended
+    ]
+);
@@ -0,0 +1,198 @@
+/*
+  Copyright 2025 Will Murnane
+
+  Licensed under the Apache License, Version 2.0 (the "License");
+  you may not use this file except in compliance with the License.
+  You may obtain a copy of the License at
+
+      http://www.apache.org/licenses/LICENSE-2.0
+
+  Unless required by applicable law or agreed to in writing, software
+  distributed under the License is distributed on an "AS IS" BASIS,
+  WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+  See the License for the specific language governing permissions and
+  limitations under the License.
+*/
+
+//! Token implications: holding one token can imply holding others.
+//!
+//! The edges live in `access_implications`, which must stay acyclic. Each backend caches the
+//! graph, and the expansion of the token set it last expanded, until the table changes; a policy
+//! that evaluates every row against the same session tokens therefore expands them once.
+
+use crate::storage::{StoredExpression, StoredTokens};
+use crate::{catalog, AccessTokens};
+use pgrx::pg_sys::panic::ErrorReport;
+use pgrx::prelude::*;
+use pgrx::{function_name, PgLogLevel, PgSqlErrorCode, PgTrigger, PgTriggerError};
+use std::cell::{Cell, RefCell};
+use std::collections::{HashMap, HashSet};
+
+/// Each token's direct implications.
+type Graph = HashMap<String, Vec<String>>;
+
+thread_local! {
+    static GRAPH: RefCell<Option<Graph>> = const { RefCell::new(None) };
+    /// The tokens last expanded, and their expansion.
+    static EXPANDED: RefCell<Option<(Vec<String>, StoredTokens)>> = const { RefCell::new(None) };
+    static TABLE: Cell<pg_sys::Oid> = const { Cell::new(pg_sys::InvalidOid) };
+}
+
+pub(crate) fn init() {
+    // SAFETY: called once per backend from _PG_init; the callback lives as long as the library.
+    unsafe {
+        pg_sys::CacheRegisterRelcacheCallback(Some(relcache_invalidated), pg_sys::Datum::from(0))
+    };
+}
+
+#[pg_guard]
+unsafe extern "C-unwind" fn relcache_invalidated(_arg: pg_sys::Datum, relid: pg_sys::Oid) {
+    if relid == pg_sys::InvalidOid || relid == TABLE.get() {
+        GRAPH.set(None);
+        EXPANDED.set(None);
+    }
+}
+
+fn load() -> Graph {
+    let query = format!(
+        "SELECT token, implies FROM {}",
+        catalog::qualified("access_implications")
+    );
+    let (oid, edges) = catalog::as_superuser(|| {
+        let edges = Spi::connect(|client| {
+            client
+                .select(&query, None, &[])?
+                .map(|row| Ok((row.get::<String>(1)?, row.get::<String>(2)?)))
+                .collect::<Result<Vec<_>, pgrx::spi::Error>>()
+        });
+        (catalog::table_oid("access_implications"), edges)
+    });
+    if let Some(oid) = oid {
+        TABLE.set(oid);
+    }
+    let mut graph = Graph::new();
+    for edge in edges.unwrap_or_else(|e| panic!("failed to read token implications: {e}")) {
+        if let (Some(token), Some(implies)) = edge {
+            graph.entry(token).or_default().push(implies);
+        }
+    }
+    graph
+}
+
+/// `tokens` and everything they imply, directly or indirectly.
+pub(crate) fn expand(tokens: &StoredTokens) -> StoredTokens {
+    if let Some(expanded) = EXPANDED.with_borrow(|cached| {
+        cached
+            .as_ref()
+            .filter(|(key, _)| key.as_slice() == tokens.tokens())
+            .map(|(_, expanded)| expanded.clone())
+    }) {
+        return expanded;
+    }
+    if GRAPH.with_borrow(Option::is_none) {
+        let graph = load();
+        GRAPH.set(Some(graph));
+    }
+    let closure = GRAPH.with_borrow(|graph| {
+        let graph = graph.as_ref().expect("graph loaded above");
+        let mut closure: HashSet<&str> = HashSet::new();
+        let mut pending: Vec<&str> = tokens.tokens().iter().map(String::as_str).collect();
+        while let Some(token) = pending.pop() {
+            if closure.insert(token) {
+                pending.extend(graph.get(token).into_iter().flatten().map(String::as_str));
+            }
+        }
+        closure.into_iter().map(str::to_string).collect::<Vec<_>>()
+    });
+    let expanded = StoredTokens::from(
+        &AccessTokens::from_values(&closure)
+            .unwrap_or_else(|e| panic!("implied tokens {closure:?} are not valid: {e:?}")),
+    );
+    EXPANDED.set(Some((tokens.tokens().to_vec(), expanded.clone())));
+    expanded
+}
+
+/// `tokens` and every token they imply, directly or indirectly, through `access_implications`.
+#[pg_extern(stable, parallel_safe)]
+fn access_expand_tokens(tokens: StoredTokens) -> StoredTokens {
+    expand(&tokens)
+}
+
+/// Whether `tokens`, together with everything they imply, satisfy `expression`.
+#[pg_extern(stable, parallel_safe)]
+fn access_evaluate_implied(expression: StoredExpression, tokens: StoredTokens) -> bool {
+    expression.evaluate(&expand(&tokens))
+}
+
+/// A path from `start` back to itself, if there is one.
+fn cycle_from<'a>(graph: &'a Graph, start: &'a str) -> Option<Vec<&'a str>> {
+    let mut path = vec![start];
+    let mut visited = HashSet::new();
+    let mut stack = vec![graph.get(start).into_iter().flatten()];
+    while let Some(edges) = stack.last_mut() {
+        match edges.next() {
+            Some(next) if next == start => {
+                path.push(next);
+                return Some(path);
+            }
+            Some(next) if visited.insert(next.as_str()) => {
+                path.push(next);
+                stack.push(graph.get(next.as_str()).into_iter().flatten());
+            }
+            Some(_) => {}
+            None => {
+                stack.pop();
+                path.pop();
+            }
+        }
+    }
+    None
+}
+
+/// Reject changes to `access_implications` that would make a token imply itself.
+#[pg_trigger]
+fn access_implications_acyclic<'a>(
+    _trigger: &'a PgTrigger<'a>,
+) -> Result<Option<PgHeapTuple<'a, AllocatedByPostgres>>, PgTriggerError> {
+    let graph = load();
+    let mut starts: Vec<&String> = graph.keys().collect();
+    starts.sort();
+    if let Some(cycle) = starts
+        .into_iter()
+        .find_map(|start| cycle_from(&graph, start))
+    {
+        ErrorReport::new(
+            PgSqlErrorCode::ERRCODE_INVALID_RECURSION,
+            "token implications must not form a cycle",
+            function_name!(),
+        )
+        .set_detail(format!("{}.", cycle.join(" implies ")))
+        .report(PgLogLevel::ERROR);
+    }
+    Ok(None)
+}
+
+extension_sql!(
+    r#"
+CREATE TABLE access_implications (
+    token text NOT NULL,
+    implies text NOT NULL,
+    PRIMARY KEY (token, implies),
+    CHECK (token <> '' AND implies <> '')
+);
+REVOKE ALL ON access_implications FROM PUBLIC;
+SELECT pg_catalog.pg_extension_config_dump('access_implications', '');
+
+CREATE TRIGGER access_implications_acyclic
+    AFTER INSERT OR UPDATE ON access_implications
+    FOR EACH STATEMENT EXECUTE FUNCTION access_implications_acyclic();
+CREATE TRIGGER access_implications_changed
+    AFTER INSERT OR UPDATE OR DELETE OR TRUNCATE ON access_implications
+    FOR EACH STATEMENT EXECUTE FUNCTION access_catalog_changed();
+
+COMMENT ON TABLE access_implications IS
+    'Pairs of tokens where holding the first implies holding the second, as expanded by access_expand_tokens()';
+"#,
+    name = "access_implications",
+    requires = [catalog::access_catalog_changed, access_implications_acyclic]
+);
@@ -0,0 +1,177 @@
+/*
+  Copyright 2025 Will Murnane
+
+  Licensed under the Apache License, Version 2.0 (the "License");
+  you may not use this file except in compliance with the License.
+  You may obtain a copy of the License at
+
+      http://www.apache.org/licenses/LICENSE-2.0
+
+  Unless required by applicable law or agreed to in writing, software
+  distributed under the License is distributed on an "AS IS" BASIS,
+  WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+  See the License for the specific language governing permissions and
+  limitations under the License.
+*/
+
+//! Type definitions and text I/O for `accessexpression` and `accesstokens`.
+//!
+//! `InOutFuncs::input` has no way to see the `fcinfo` it was called with, so it can only fail by
+//! raising. The input functions here are written out by hand so that, on PostgreSQL 16 and later,
+//! a bad label is saved into the caller's `ErrorSaveContext` instead. That is what
+//! `pg_input_is_valid`, `pg_input_error_info` and `COPY ... (ON_ERROR ignore)` rely on. Older
+//! servers have no such context and always get a hard error.
+
+use crate::syntax::{self, InputError};
+use crate::{analyze, wire};
+use crate::{AccessExpression, AccessTokens};
+use pgrx::prelude::*;
+use pgrx::StringInfo;
+use std::ffi::CStr;
+
+extension_sql!(
+    r#"
+CREATE TYPE accessexpression;
+CREATE TYPE accesstokens;
+"#,
+    name = "access_shell_types",
+    bootstrap
+);
+
+#[pg_extern(immutable, parallel_safe, strict)]
+fn accessexpression_in(input: &CStr, fcinfo: pg_sys::FunctionCallInfo) -> Option<AccessExpression> {
+    let parsed = syntax::utf8("accessexpression", input).and_then(|text| {
+        ::access::expression(text)
+            .map(AccessExpression)
+            .map_err(|e| {
+                syntax::invalid_input(
+                    "accessexpression",
+                    text,
+                    syntax::parse_expression(text).err(),
+                    e,
+                )
+            })
+    });
+    soft(fcinfo, c"accessexpression_in", parsed)
+}
+
+#[pg_extern(immutable, parallel_safe, strict)]
+fn accessexpression_out(value: AccessExpression) -> &'static CStr {
+    let mut buffer = StringInfo::new();
+    buffer.push_str(&value.0.to_string());
+    buffer.leak_cstr()
+}
+
+#[pg_extern(immutable, parallel_safe, strict)]
+fn accesstokens_in(input: &CStr, fcinfo: pg_sys::FunctionCallInfo) -> Option<AccessTokens> {
+    let parsed = syntax::utf8("accesstokens", input).and_then(|text| {
+        ::access::tokens(text).map(AccessTokens).map_err(|e| {
+            syntax::invalid_input("accesstokens", text, syntax::parse_tokens(text).err(), e)
+        })
+    });
+    soft(fcinfo, c"accesstokens_in", parsed)
+}
+
+#[pg_extern(immutable, parallel_safe, strict)]
+fn accesstokens_out(value: AccessTokens) -> &'static CStr {
+    let mut buffer = StringInfo::new();
+    buffer.push_str(&value.0.to_string());
+    buffer.leak_cstr()
+}
+
+extension_sql!(
+    r#"
+CREATE TYPE accessexpression (
+    INTERNALLENGTH = variable,
+    INPUT = accessexpression_in,
+    OUTPUT = accessexpression_out,
+    RECEIVE = accessexpression_recv,
+    SEND = accessexpression_send,
+    ANALYZE = accessexpression_analyze,
+    STORAGE = extended
+);
+"#,
+    name = "accessexpression",
+    creates = [Type(AccessExpression)],
+    requires = [
+        accessexpression_in,
+        accessexpression_out,
+        wire::accessexpression_recv,
+        wire::accessexpression_send,
+        analyze::accessexpression_analyze
+    ]
+);
+
+extension_sql!(
+    r#"
+CREATE TYPE accesstokens (
+    INTERNALLENGTH = variable,
+    INPUT = accesstokens_in,
+    OUTPUT = accesstokens_out,
+    RECEIVE = accesstokens_recv,
+    SEND = accesstokens_send,
+    STORAGE = extended
+);
+"#,
+    name = "accesstokens",
+    creates = [Type(AccessTokens)],
+    requires = [
+        accesstokens_in,
+        accesstokens_out,
+        wire::accesstokens_recv,
+        wire::accesstokens_send
+    ]
+);
+
+/// Hand the result of an input function back to PostgreSQL. Errors become soft errors when the
+/// caller asked for them, and are raised otherwise; either way a failed parse returns NULL, which
+/// PostgreSQL ignores once `escontext->error_occurred` is set.
+fn soft<T>(
+    fcinfo: pg_sys::FunctionCallInfo,
+    funcname: &CStr,
+    result: Result<T, InputError>,
+) -> Option<T> {
+    match result {
+        Ok(value) => Some(value),
+        Err(error) => {
+            save(fcinfo, funcname, error);
+            None
+        }
+    }
+}
+
+#[cfg(any(feature = "pg16", feature = "pg17", feature = "pg18"))]
+fn save(fcinfo: pg_sys::FunctionCallInfo, funcname: &CStr, error: InputError) {
+    use std::ffi::CString;
+
+    // SAFETY: fcinfo is the live call frame for this input function, and `context`, when set, is
+    // a valid Node whose tag tells us whether it really is an ErrorSaveContext.
+    let escontext = unsafe { (*fcinfo).context };
+    if escontext.is_null() || unsafe { (*escontext).type_ } != pg_sys::NodeTag::T_ErrorSaveContext {
+        syntax::raise(error);
+    }
+    let message = CString::new(error.message).unwrap_or_default();
+    let detail = CString::new(error.detail).unwrap_or_default();
+    let filename = CString::new(file!()).unwrap_or_default();
+    // SAFETY: this mirrors the C `errsave()` macro. errsave_start() only returns true for a soft
+    // context that wants details, in which case it has pushed an ErrorData for errmsg() and
+    // friends to fill in and errsave_finish() to hand over; it never longjmps for a soft context.
+    unsafe {
+        if pg_sys::errsave_start(escontext, std::ptr::null()) {
+            pg_sys::errcode(error.code as i32);
+            pg_sys::errmsg(c"%s".as_ptr(), message.as_ptr());
+            pg_sys::errdetail(c"%s".as_ptr(), detail.as_ptr());
+            pg_sys::errsave_finish(
+                escontext,
+                filename.as_ptr(),
+                line!() as i32,
+                funcname.as_ptr(),
+            );
+        }
+    }
+}
+
+#[cfg(not(any(feature = "pg16", feature = "pg17", feature = "pg18")))]
+fn save(_fcinfo: pg_sys::FunctionCallInfo, _funcname: &CStr, error: InputError) {
+    syntax::raise(error);
+}
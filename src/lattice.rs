@@ -0,0 +1,275 @@
+/*
+  Copyright 2025 Will Murnane
+
+  Licensed under the Apache License, Version 2.0 (the "License");
+  you may not use this file except in compliance with the License.
+  You may obtain a copy of the License at
+
+      http://www.apache.org/licenses/LICENSE-2.0
+
+  Unless required by applicable law or agreed to in writing, software
+  distributed under the License is distributed on an "AS IS" BASIS,
+  WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+  See the License for the specific language governing permissions and
+  limitations under the License.
+*/
+
+//! Multi-level security labels: a classification level plus a set of compartments.
+//!
+//! Levels are ranked in `access_levels`. One `access_label` dominates another if its level ranks
+//! at least as high and it has every compartment the other has; `access_lub` and `access_glb`
+//! are the least upper and greatest lower bounds of two labels under that order.
+//!
+//! Labels translate into the token model so they can be used with everything else: as data, a
+//! label is the expression requiring its level's token and each of its compartments, and as a
+//! clearance, it is the tokens of its own level and every level below it, plus its compartments.
+//! A clearance then satisfies a label's expression exactly when it dominates the label. Level
+//! tokens are the level name prefixed with `level:`, so compartments may not use that prefix.
+
+use crate::storage::StoredTokens;
+use crate::syntax::Expr;
+use crate::{catalog, AccessExpression, AccessTokens};
+use pgrx::pg_sys::panic::ErrorReport;
+use pgrx::prelude::*;
+use pgrx::{function_name, PgLogLevel, PgSqlErrorCode};
+use std::cell::{Cell, RefCell};
+use std::collections::BTreeSet;
+
+/// Prefixed to a level's name to make its token.
+const LEVEL_PREFIX: &str = "level:";
+
+thread_local! {
+    /// Every level and its rank, lowest first.
+    static LEVELS: RefCell<Option<Vec<(String, i32)>>> = const { RefCell::new(None) };
+    static TABLE: Cell<pg_sys::Oid> = const { Cell::new(pg_sys::InvalidOid) };
+}
+
+pub(crate) fn init() {
+    // SAFETY: called once per backend from _PG_init; the callback lives as long as the library.
+    unsafe {
+        pg_sys::CacheRegisterRelcacheCallback(Some(relcache_invalidated), pg_sys::Datum::from(0))
+    };
+}
+
+#[pg_guard]
+unsafe extern "C-unwind" fn relcache_invalidated(_arg: pg_sys::Datum, relid: pg_sys::Oid) {
+    if relid == pg_sys::InvalidOid || relid == TABLE.get() {
+        LEVELS.set(None);
+    }
+}
+
+/// Run `f` with every level and its rank, lowest first.
+fn with_levels<R>(f: impl FnOnce(&[(String, i32)]) -> R) -> R {
+    if LEVELS.with_borrow(Option::is_none) {
+        let query = format!(
+            "SELECT level, rank FROM {} ORDER BY rank",
+            catalog::qualified("access_levels")
+        );
+        let (oid, levels) = catalog::as_superuser(|| {
+            let levels = Spi::connect(|client| {
+                client
+                    .select(&query, None, &[])?
+                    .map(|row| Ok((row.get::<String>(1)?, row.get::<i32>(2)?)))
+                    .collect::<Result<Vec<_>, pgrx::spi::Error>>()
+            });
+            (catalog::table_oid("access_levels"), levels)
+        });
+        if let Some(oid) = oid {
+            TABLE.set(oid);
+        }
+        let levels = levels
+            .unwrap_or_else(|e| panic!("failed to read classification levels: {e}"))
+            .into_iter()
+            .filter_map(|(level, rank)| level.zip(rank))
+            .collect();
+        LEVELS.set(Some(levels));
+    }
+    LEVELS.with_borrow(|levels| f(levels.as_deref().expect("levels loaded above")))
+}
+
+/// A label read from an `access_label`, with its level's rank looked up.
+struct Label {
+    level: String,
+    rank: i32,
+    compartments: BTreeSet<String>,
+}
+
+impl Label {
+    fn new(level: String, compartments: impl IntoIterator<Item = String>) -> Self {
+        let Some(rank) = with_levels(|levels| {
+            levels
+                .iter()
+                .find(|(name, _)| *name == level)
+                .map(|(_, rank)| *rank)
+        }) else {
+            ErrorReport::new(
+                PgSqlErrorCode::ERRCODE_INVALID_PARAMETER_VALUE,
+                format!("unknown classification level \"{level}\""),
+                function_name!(),
+            )
+            .set_hint("Levels are listed in access_levels.")
+            .report(PgLogLevel::ERROR);
+            unreachable!("ERROR-level reports do not return")
+        };
+        let compartments: BTreeSet<String> = compartments
+            .into_iter()
+            .filter(|compartment| !compartment.is_empty())
+            .collect();
+        if let Some(compartment) = compartments
+            .iter()
+            .find(|compartment| compartment.starts_with(LEVEL_PREFIX))
+        {
+            ErrorReport::new(
+                PgSqlErrorCode::ERRCODE_INVALID_PARAMETER_VALUE,
+                format!("compartment \"{compartment}\" may not start with \"{LEVEL_PREFIX}\""),
+                function_name!(),
+            )
+            .set_detail("Tokens starting with it stand for classification levels.")
+            .report(PgLogLevel::ERROR);
+        }
+        Label {
+            level,
+            rank,
+            compartments,
+        }
+    }
+
+    fn read(label: &pgrx::composite_type!("access_label")) -> Self {
+        let level = label
+            .get_by_name::<String>("level")
+            .ok()
+            .flatten()
+            .unwrap_or_else(|| panic!("an access_label must have a level"));
+        let compartments = label
+            .get_by_name::<Vec<Option<String>>>("compartments")
+            .ok()
+            .flatten()
+            .unwrap_or_default();
+        Label::new(level, compartments.into_iter().flatten())
+    }
+
+    fn write(self) -> pgrx::composite_type!('static, "access_label") {
+        let mut label =
+            PgHeapTuple::new_composite_type("access_label").expect("the access_label type exists");
+        label
+            .set_by_name("level", self.level)
+            .expect("access_label has a text level");
+        label
+            .set_by_name(
+                "compartments",
+                self.compartments.into_iter().collect::<Vec<_>>(),
+            )
+            .expect("access_label has text[] compartments");
+        label
+    }
+
+    fn dominates(&self, other: &Label) -> bool {
+        self.rank >= other.rank && self.compartments.is_superset(&other.compartments)
+    }
+}
+
+/// A label with the given level, which must be listed in `access_levels`, and compartments.
+#[pg_extern(stable, parallel_safe, requires = ["access_label"])]
+fn access_label(
+    level: String,
+    compartments: default!(Vec<Option<String>>, "'{}'"),
+) -> pgrx::composite_type!('static, "access_label") {
+    Label::new(level, compartments.into_iter().flatten()).write()
+}
+
+/// The expression a clearance must satisfy to read data with this label.
+#[pg_extern(stable, parallel_safe, requires = ["access_label"])]
+fn access_label_expression(label: pgrx::composite_type!("access_label")) -> AccessExpression {
+    let label = Label::read(&label);
+    let mut parts = vec![Expr::Token(format!("{LEVEL_PREFIX}{}", label.level))];
+    parts.extend(label.compartments.into_iter().map(Expr::Token));
+    let tree = match parts.len() {
+        1 => parts.pop().expect("one part"),
+        _ => Expr::And(parts),
+    };
+    AccessExpression::from_tree(Some(&tree))
+        .unwrap_or_else(|e| panic!("label expression \"{tree}\" is not valid: {e:?}"))
+}
+
+/// The tokens held by someone cleared to this label.
+#[pg_extern(stable, parallel_safe, requires = ["access_label"])]
+fn access_label_tokens(label: pgrx::composite_type!("access_label")) -> StoredTokens {
+    let label = Label::read(&label);
+    let mut tokens: Vec<String> = with_levels(|levels| {
+        levels
+            .iter()
+            .filter(|(_, rank)| *rank <= label.rank)
+            .map(|(level, _)| format!("{LEVEL_PREFIX}{level}"))
+            .collect()
+    });
+    tokens.extend(label.compartments);
+    let tokens = AccessTokens::from_values(&tokens)
+        .unwrap_or_else(|e| panic!("label tokens {tokens:?} are not valid: {e:?}"));
+    StoredTokens::from(&tokens)
+}
+
+/// Whether `a` dominates `b`: its level ranks at least as high, and it has all of `b`'s
+/// compartments.
+#[pg_extern(stable, parallel_safe, requires = ["access_label"])]
+fn access_dominates(
+    a: pgrx::composite_type!("access_label"),
+    b: pgrx::composite_type!("access_label"),
+) -> bool {
+    Label::read(&a).dominates(&Label::read(&b))
+}
+
+/// The least upper bound of `a` and `b`: the higher level, and the compartments of both.
+#[pg_extern(stable, parallel_safe, requires = ["access_label"])]
+fn access_lub(
+    a: pgrx::composite_type!("access_label"),
+    b: pgrx::composite_type!("access_label"),
+) -> pgrx::composite_type!('static, "access_label") {
+    let (a, b) = (Label::read(&a), Label::read(&b));
+    let (higher, lower) = if a.rank >= b.rank { (a, b) } else { (b, a) };
+    Label {
+        compartments: &higher.compartments | &lower.compartments,
+        ..higher
+    }
+    .write()
+}
+
+/// The greatest lower bound of `a` and `b`: the lower level, and the compartments they share.
+#[pg_extern(stable, parallel_safe, requires = ["access_label"])]
+fn access_glb(
+    a: pgrx::composite_type!("access_label"),
+    b: pgrx::composite_type!("access_label"),
+) -> pgrx::composite_type!('static, "access_label") {
+    let (a, b) = (Label::read(&a), Label::read(&b));
+    let (lower, higher) = if a.rank <= b.rank { (a, b) } else { (b, a) };
+    Label {
+        compartments: &lower.compartments & &higher.compartments,
+        ..lower
+    }
+    .write()
+}
+
+extension_sql!(
+    r#"
+CREATE TABLE access_levels (
+    level text PRIMARY KEY CHECK (level <> ''),
+    rank integer NOT NULL UNIQUE
+);
+REVOKE ALL ON access_levels FROM PUBLIC;
+GRANT SELECT ON access_levels TO PUBLIC;
+SELECT pg_catalog.pg_extension_config_dump('access_levels', '');
+
+CREATE TRIGGER access_levels_changed
+    AFTER INSERT OR UPDATE OR DELETE OR TRUNCATE ON access_levels
+    FOR EACH STATEMENT EXECUTE FUNCTION access_catalog_changed();
+
+COMMENT ON TABLE access_levels IS
+    'Classification levels for access_label, ordered by rank from lowest to highest';
+
+CREATE TYPE access_label AS (
+    level text,
+    compartments text[]
+);
+"#,
+    name = "access_label",
+    requires = [catalog::access_catalog_changed]
+);
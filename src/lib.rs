@@ -17,40 +17,88 @@
 use pgrx::prelude::*;
 
 ::pgrx::pg_module_magic!(name, version);
-use serde::Deserialize;
-use serde::Serialize;
 
-#[derive(PostgresType, Serialize, Eq, PartialEq, Deserialize, PostgresEq)]
-#[serde(transparent)]
-#[inoutfuncs]
+mod analyze;
+mod authorization;
+mod btree;
+mod catalog;
+mod combine;
+mod coverage;
+mod datum;
+mod gin;
+mod guc;
+mod hash;
+mod implications;
+mod io;
+mod lattice;
+mod planner;
+mod principals;
+mod protect;
+mod roles;
+mod seclabel;
+mod signed;
+mod storage;
+mod syntax;
+mod tokens;
+mod wire;
+mod write;
+
+#[pg_guard]
+pub extern "C-unwind" fn _PG_init() {
+    authorization::init();
+    coverage::init();
+    guc::init();
+    implications::init();
+    lattice::init();
+    principals::init();
+    seclabel::init();
+    signed::init();
+}
+
+#[derive(Eq, PartialEq)]
 pub struct AccessExpression(::access::AccessExpression);
 
-impl InOutFuncs for AccessExpression {
-    fn input(input: &::std::ffi::CStr) -> Self {
-        AccessExpression(::access::expression(input.to_str().unwrap()).unwrap())
+impl AccessExpression {
+    /// The canonical expression as a tree, or `None` for the empty expression.
+    pub(crate) fn tree(&self) -> Option<syntax::Expr> {
+        syntax::parse_expression(&self.0.to_string())
+            .unwrap_or_else(|_| panic!("canonical expression \"{}\" failed to parse", self.0))
     }
 
-    fn output(&self, buffer: &mut ::pgrx::StringInfo) {
-        buffer.push_str(&format!("{}", self.0))
+    /// Build (and canonicalize) an expression from a tree; `None` is the empty expression.
+    pub(crate) fn from_tree(tree: Option<&syntax::Expr>) -> Result<Self, impl ::std::fmt::Debug> {
+        let text = tree.map_or_else(String::new, |tree| tree.to_string());
+        ::access::expression(&text).map(AccessExpression)
     }
 }
-#[derive(PostgresType, Eq, PartialEq, Serialize, Deserialize, PostgresEq)]
-#[serde(transparent)]
-#[inoutfuncs]
+
+#[derive(Eq, PartialEq)]
 pub struct AccessTokens(::access::AccessTokens);
-impl InOutFuncs for AccessTokens {
-    fn input(input: &::std::ffi::CStr) -> Self {
-        AccessTokens(::access::tokens(input.to_str().unwrap()).unwrap())
+
+impl AccessTokens {
+    /// The unescaped token values, in canonical order.
+    pub(crate) fn values(&self) -> Vec<String> {
+        syntax::parse_tokens(&self.0.to_string())
+            .unwrap_or_else(|_| panic!("canonical tokens \"{}\" failed to parse", self.0))
     }
 
-    fn output(&self, buffer: &mut ::pgrx::StringInfo) {
-        buffer.push_str(&format!("{}", self.0))
+    /// Build (and canonicalize) a token set from unescaped token values.
+    pub(crate) fn from_values<S: AsRef<str>>(values: &[S]) -> Result<Self, impl ::std::fmt::Debug> {
+        let text = values
+            .iter()
+            .map(|value| syntax::quote(value.as_ref()))
+            .collect::<Vec<_>>()
+            .join(",");
+        ::access::tokens(&text).map(AccessTokens)
     }
 }
 
-#[pg_extern]
-pub fn access_evaluate(expression: AccessExpression, tokens: AccessTokens) -> bool {
-    ::access::evaluate(&expression.0, &tokens.0)
+#[pg_extern(immutable, parallel_safe, support = planner::access_evaluate_support)]
+pub fn access_evaluate(
+    expression: storage::StoredExpression,
+    tokens: storage::StoredTokens,
+) -> bool {
+    expression.evaluate(&tokens)
 }
 /// This module is required by `cargo pgrx test` invocations.
 /// It must be visible at the root of your extension crate.
@@ -63,7 +111,7 @@ pub mod pg_test {
     #[must_use]
     pub fn postgresql_conf_options() -> Vec<&'static str> {
         // return any postgresql.conf settings that are required for your tests
-        vec![]
+        vec!["shared_preload_libraries = 'access_pgrx'"]
     }
 }
 
@@ -74,7 +122,705 @@ mod tests {
 
     #[pg_test]
     fn test_access_expression() {
-        let val = Spi::get_one::<SomeEnum>(r#"SELECT 'a'::AccessExpression"#);
-        assert_eq!(val, Ok(Some(AccessExpression::Token("a".to_string()))));
+        let val = Spi::get_one::<String>(r#"SELECT 'a'::AccessExpression::text"#);
+        assert_eq!(val, Ok(Some("a".to_string())));
+    }
+
+    #[pg_test(
+        error = "invalid input syntax for type accessexpression: cannot mix \"&\" (at character 2) and \"|\" without parentheses"
+    )]
+    fn test_access_expression_mixed_junctions() {
+        Spi::run(r#"SELECT 'A&B|C'::AccessExpression"#).unwrap();
+    }
+
+    #[pg_test(error = "invalid input syntax for type accesstokens: unterminated quoted token")]
+    fn test_access_tokens_unterminated_quote() {
+        Spi::run(r#"SELECT 'A,"B'::AccessTokens"#).unwrap();
+    }
+
+    #[cfg(any(feature = "pg16", feature = "pg17", feature = "pg18"))]
+    #[pg_test]
+    fn test_soft_input_errors() {
+        let valid = Spi::get_one::<bool>(r#"SELECT pg_input_is_valid('A&|B', 'accessexpression')"#);
+        assert_eq!(valid, Ok(Some(false)));
+        let valid = Spi::get_one::<bool>(r#"SELECT pg_input_is_valid('A,"B', 'accesstokens')"#);
+        assert_eq!(valid, Ok(Some(false)));
+        let valid =
+            Spi::get_one::<bool>(r#"SELECT pg_input_is_valid('A&(B|C)', 'accessexpression')"#);
+        assert_eq!(valid, Ok(Some(true)));
+        let code = Spi::get_one::<String>(
+            r#"SELECT sql_error_code FROM pg_input_error_info('A&B|C', 'accessexpression')"#,
+        );
+        assert_eq!(code, Ok(Some("22P02".to_string())));
+    }
+
+    #[pg_test]
+    fn test_storage_round_trip() {
+        Spi::run(
+            r#"CREATE TABLE labels (e accessexpression, t accesstokens);
+               INSERT INTO labels VALUES ('(b&D)|Z|(a|c)', '":)",A,"…",Z'), ('', '')"#,
+        )
+        .unwrap();
+        let val = Spi::get_one::<String>(
+            r#"SELECT string_agg(e::text || '/' || t::text, ';' ORDER BY e::text) FROM labels"#,
+        );
+        assert_eq!(val, Ok(Some("/;Z|a|c|(D&b)/A,Z,\":)\",\"…\"".to_string())));
+        let val = Spi::get_one::<i32>(r#"SELECT min(access_storage_version(e)) FROM labels"#);
+        assert_eq!(val, Ok(Some(1)));
+        let val = Spi::get_one::<i64>(
+            r#"SELECT count(*) FROM labels WHERE access_evaluate(e, 'D,b'::accesstokens)"#,
+        );
+        assert_eq!(val, Ok(Some(2)));
+    }
+
+    #[pg_test]
+    fn test_btree_ordering() {
+        Spi::run(
+            r#"CREATE TABLE dictionary (label accessexpression UNIQUE);
+               INSERT INTO dictionary VALUES ('b|a'), ('A&B'), ('')"#,
+        )
+        .unwrap();
+        let val = Spi::get_one::<String>(
+            r#"SELECT string_agg(label::text, ';' ORDER BY label) FROM dictionary"#,
+        );
+        assert_eq!(val, Ok(Some(";A&B;a|b".to_string())));
+        let val = Spi::get_one::<bool>(r#"SELECT 'A,B'::accesstokens < 'B'::accesstokens"#);
+        assert_eq!(val, Ok(Some(true)));
+    }
+
+    #[pg_test(error = "duplicate key value violates unique constraint \"dictionary_label_key\"")]
+    fn test_btree_unique_canonical() {
+        Spi::run(
+            r#"CREATE TABLE dictionary (label accessexpression UNIQUE);
+               INSERT INTO dictionary VALUES ('b|a'), ('a|b')"#,
+        )
+        .unwrap();
+    }
+
+    #[pg_test]
+    fn test_hash_opclass() {
+        let val = Spi::get_one::<bool>(
+            r#"SELECT accessexpression_hash('b|a') = accessexpression_hash('a|b')
+                  AND accessexpression_hash_extended('b|a', 0)::bit(32)
+                      = accessexpression_hash('a|b')::bit(32)"#,
+        );
+        assert_eq!(val, Ok(Some(true)));
+        let val = Spi::get_one::<i64>(
+            r#"SELECT count(*) FROM (SELECT DISTINCT l FROM (VALUES
+                   ('b|a'::accessexpression), ('a|b'), ('A&B')) v(l)) d"#,
+        );
+        assert_eq!(val, Ok(Some(2)));
+        Spi::run(r#"CREATE TABLE hashed (label accesstokens) PARTITION BY HASH (label)"#).unwrap();
+    }
+
+    /// Create and analyze `name` with 1000 rows whose `restriction` cycles through `A`, `A&B`,
+    /// `C|D` and the empty expression.
+    fn labelled_table(name: &str) {
+        Spi::run(&format!(
+            r#"CREATE TABLE {name} (id serial, restriction accessexpression);
+               INSERT INTO {name} (restriction)
+                   SELECT CASE i % 4 WHEN 0 THEN 'A' WHEN 1 THEN 'A&B' WHEN 2 THEN 'C|D' ELSE '' END
+                   FROM generate_series(1, 1000) i;
+               ANALYZE {name}"#
+        ))
+        .unwrap();
+    }
+
+    #[pg_test]
+    fn test_gin_index_scan() {
+        labelled_table("gated");
+        Spi::run(
+            r#"CREATE INDEX ON gated USING gin (restriction);
+               ANALYZE gated;
+               SET enable_seqscan = off"#,
+        )
+        .unwrap();
+        let val = Spi::get_one::<i64>(
+            r#"SELECT count(*) FROM gated WHERE restriction <@ 'A,D'::accesstokens"#,
+        );
+        assert_eq!(val, Ok(Some(750)));
+        let val = Spi::get_one::<i64>(
+            r#"SELECT count(*) FROM gated WHERE NOT access_evaluate(restriction, 'A,D')"#,
+        );
+        assert_eq!(val, Ok(Some(250)));
+        let plan = Spi::get_one::<String>(
+            r#"EXPLAIN (COSTS OFF) SELECT * FROM gated WHERE restriction <@ 'A,D'::accesstokens"#,
+        );
+        assert!(plan.unwrap().unwrap().contains("Bitmap Heap Scan"));
+    }
+
+    #[pg_test]
+    fn test_planner_support() {
+        labelled_table("estimated");
+        let plan = Spi::get_one::<String>(
+            r#"EXPLAIN SELECT * FROM estimated WHERE access_evaluate(restriction, 'A,D')"#,
+        );
+        assert!(plan.unwrap().unwrap().contains("rows=750 "));
+        let plan = Spi::get_one::<String>(
+            r#"EXPLAIN SELECT * FROM estimated WHERE restriction <@ 'A,D'::accesstokens"#,
+        );
+        assert!(plan.unwrap().unwrap().contains("rows=750 "));
+        let plan = Spi::get_one::<String>(
+            r#"EXPLAIN SELECT * FROM estimated WHERE 'A,D'::accesstokens @> restriction"#,
+        );
+        assert!(plan.unwrap().unwrap().contains("rows=750 "));
+        Spi::run(r#"SET access.tokens = 'A,D'"#).unwrap();
+        let plan = Spi::get_one::<String>(
+            r#"EXPLAIN SELECT * FROM estimated WHERE access_evaluate(restriction)"#,
+        );
+        assert!(plan.unwrap().unwrap().contains("rows=750 "));
+        Spi::run(r#"RESET access.tokens"#).unwrap();
+        let plan: Vec<String> = Spi::connect(|client| {
+            client
+                .select(
+                    r#"EXPLAIN (VERBOSE, COSTS OFF) SELECT access_evaluate('A&B', 'A')"#,
+                    None,
+                    &[],
+                )
+                .unwrap()
+                .filter_map(|row| row.get::<String>(1).unwrap())
+                .collect()
+        });
+        assert!(plan.iter().any(|line| line.trim() == "Output: false"));
+    }
+
+    #[pg_test]
+    fn test_analyze_token_stats() {
+        labelled_table("counted");
+        let val = Spi::get_one::<String>(
+            r#"SELECT string_agg(token || '=' || frequency || '/' || estimated_rows, ',' ORDER BY token)
+               FROM access_stats WHERE tablename = 'counted'"#,
+        );
+        assert_eq!(
+            val,
+            Ok(Some(
+                "A=0.5/500,B=0.25/250,C=0.25/250,D=0.25/250".to_string()
+            ))
+        );
+        let val = Spi::get_one::<Vec<f32>>(
+            r#"SELECT elem_count_histogram FROM pg_stats WHERE tablename = 'counted'"#,
+        );
+        assert_eq!(val.unwrap().unwrap().last(), Some(&1.25));
+    }
+
+    #[pg_test]
+    fn test_session_tokens() {
+        Spi::run(r#"SET access.tokens = 'Z,A'"#).unwrap();
+        let val = Spi::get_one::<String>(r#"SELECT access_current_tokens()::text"#);
+        assert_eq!(val, Ok(Some("A,Z".to_string())));
+        let val = Spi::get_one::<bool>(r#"SELECT access_evaluate('A&(B|Z)')"#);
+        assert_eq!(val, Ok(Some(true)));
+        Spi::run(r#"RESET access.tokens"#).unwrap();
+        let val = Spi::get_one::<bool>(r#"SELECT access_evaluate('A')"#);
+        assert_eq!(val, Ok(Some(false)));
+    }
+
+    #[pg_test(error = "invalid value for parameter \"access.tokens\": \"A,\"B\"")]
+    fn test_session_tokens_rejected() {
+        Spi::run(r#"SET access.tokens = 'A,"B'"#).unwrap();
+    }
+
+    #[pg_test]
+    fn test_assume_tokens() {
+        Spi::run(r#"CREATE ROLE analyst"#).unwrap();
+        Spi::run(r#"INSERT INTO access_authorizations VALUES ('analyst', 'A,B')"#).unwrap();
+        Spi::run(r#"SET ROLE analyst"#).unwrap();
+        let val =
+            Spi::get_one::<String>(r#"SELECT access_assume_tokens('B,C', strict => false)::text"#);
+        assert_eq!(val, Ok(Some("B".to_string())));
+        Spi::run(r#"SET access.tokens = 'A,B,C'"#).unwrap();
+        let val = Spi::get_one::<String>(r#"SELECT access_current_tokens()::text"#);
+        assert_eq!(val, Ok(Some("B".to_string())));
+        Spi::run(r#"RESET ROLE"#).unwrap();
+        let val = Spi::get_one::<String>(r#"SELECT access_current_tokens()::text"#);
+        assert_eq!(val, Ok(Some("A,B,C".to_string())));
+        Spi::run(r#"RESET access.tokens"#).unwrap();
+    }
+
+    #[pg_test(error = "role \"intern\" may not assume all of the requested access tokens")]
+    fn test_assume_tokens_beyond_maximum() {
+        Spi::run(r#"CREATE ROLE intern"#).unwrap();
+        Spi::run(r#"INSERT INTO access_authorizations VALUES ('intern', 'A,B')"#).unwrap();
+        Spi::run(r#"SET ROLE intern"#).unwrap();
+        Spi::run(r#"SELECT access_assume_tokens('C')"#).unwrap();
+    }
+
+    #[pg_test]
+    fn test_session_tokens_ignored_with_maximum() {
+        Spi::run(r#"CREATE ROLE contractor"#).unwrap();
+        Spi::run(r#"INSERT INTO access_authorizations VALUES ('contractor', 'A,B')"#).unwrap();
+        Spi::run(r#"SET ROLE contractor"#).unwrap();
+        Spi::run(r#"SET access.tokens = 'B,C'"#).unwrap();
+        let val = Spi::get_one::<String>(r#"SELECT access_current_tokens()::text"#);
+        assert_eq!(val, Ok(Some("".to_string())));
+        let val = Spi::get_one::<bool>(r#"SELECT access_evaluate('B')"#);
+        assert_eq!(val, Ok(Some(false)));
+        Spi::run(r#"SELECT access_assume_tokens('B')"#).unwrap();
+        let val = Spi::get_one::<String>(r#"SELECT access_current_tokens()::text"#);
+        assert_eq!(val, Ok(Some("B".to_string())));
+        Spi::run(r#"RESET ROLE"#).unwrap();
+        Spi::run(r#"RESET access.tokens"#).unwrap();
+    }
+
+    #[pg_test]
+    fn test_principal_tokens() {
+        Spi::run(r#"CREATE ROLE clerk"#).unwrap();
+        Spi::run(r#"INSERT INTO access_principals VALUES ('clerk', 'B,A')"#).unwrap();
+        let val = Spi::get_one::<String>(r#"SELECT access_principal_tokens('clerk')::text"#);
+        assert_eq!(val, Ok(Some("A,B".to_string())));
+        Spi::run(r#"UPDATE access_principals SET tokens = 'C' WHERE role = 'clerk'"#).unwrap();
+        let val = Spi::get_one::<String>(r#"SELECT access_principal_tokens('clerk')::text"#);
+        assert_eq!(val, Ok(Some("C".to_string())));
+        let val = Spi::get_one::<String>(r#"SELECT access_principal_tokens()::text"#);
+        assert_eq!(val, Ok(Some("".to_string())));
+    }
+
+    #[pg_test(error = "permission denied to read the access tokens of role \"manager\"")]
+    fn test_principal_tokens_of_other_role() {
+        Spi::run(r#"CREATE ROLE manager; CREATE ROLE nosy"#).unwrap();
+        Spi::run(r#"INSERT INTO access_principals VALUES ('manager', 'A')"#).unwrap();
+        Spi::run(r#"SET ROLE nosy"#).unwrap();
+        Spi::run(r#"SELECT access_principal_tokens('manager')"#).unwrap();
+    }
+
+    #[pg_test]
+    fn test_tokens_from_roles() {
+        Spi::run(r#"CREATE ROLE tok_a; CREATE ROLE tok_b; CREATE ROLE department"#).unwrap();
+        Spi::run(
+            r#"CREATE ROLE employee; GRANT tok_b TO tok_a; GRANT tok_a, department TO employee"#,
+        )
+        .unwrap();
+        Spi::run(r#"SET ROLE employee"#).unwrap();
+        let val = Spi::get_one::<String>(r#"SELECT access_tokens_from_roles('tok_')::text"#);
+        assert_eq!(val, Ok(Some("a,b".to_string())));
+        let val = Spi::get_one::<String>(r#"SELECT access_tokens_from_roles()::text"#);
+        assert_eq!(val, Ok(Some("department,tok_a,tok_b".to_string())));
+        Spi::run(r#"RESET ROLE"#).unwrap();
+        #[cfg(any(feature = "pg16", feature = "pg17", feature = "pg18"))]
+        {
+            Spi::run(r#"CREATE ROLE tok_c; GRANT tok_c TO employee WITH INHERIT FALSE"#).unwrap();
+            Spi::run(r#"SET ROLE employee"#).unwrap();
+            let val = Spi::get_one::<String>(
+                r#"SELECT access_tokens_from_roles('tok_', strip_prefix => false)::text"#,
+            );
+            assert_eq!(val, Ok(Some("tok_a,tok_b".to_string())));
+            Spi::run(r#"RESET ROLE"#).unwrap();
+        }
+    }
+
+    #[pg_test]
+    fn test_implications() {
+        Spi::run(
+            r#"INSERT INTO access_implications VALUES
+                   ('AUDIT_FINANCE', 'AUDITOR'), ('AUDITOR', 'STAFF'), ('DEPT_A', 'USER')"#,
+        )
+        .unwrap();
+        let val = Spi::get_one::<String>(r#"SELECT access_expand_tokens('AUDIT_FINANCE')::text"#);
+        assert_eq!(val, Ok(Some("AUDITOR,AUDIT_FINANCE,STAFF".to_string())));
+        let val = Spi::get_one::<bool>(
+            r#"SELECT access_evaluate_implied('STAFF&USER', 'AUDIT_FINANCE,DEPT_A')"#,
+        );
+        assert_eq!(val, Ok(Some(true)));
+        let val = Spi::get_one::<bool>(r#"SELECT access_evaluate('STAFF', 'AUDIT_FINANCE')"#);
+        assert_eq!(val, Ok(Some(false)));
+    }
+
+    #[pg_test(error = "token implications must not form a cycle")]
+    fn test_implication_cycle() {
+        Spi::run(r#"INSERT INTO access_implications VALUES ('X', 'Y'), ('Y', 'Z')"#).unwrap();
+        Spi::run(r#"INSERT INTO access_implications VALUES ('Z', 'X')"#).unwrap();
+    }
+
+    #[pg_test]
+    fn test_lattice() {
+        Spi::run(
+            r#"INSERT INTO access_levels VALUES
+                   ('UNCLASSIFIED', 0), ('CONFIDENTIAL', 1), ('SECRET', 2), ('TOP SECRET', 3)"#,
+        )
+        .unwrap();
+        let val = Spi::get_one::<bool>(
+            r#"SELECT access_dominates(access_label('TOP SECRET', '{NATO}'), access_label('SECRET', '{NATO}'))"#,
+        );
+        assert_eq!(val, Ok(Some(true)));
+        let val = Spi::get_one::<bool>(
+            r#"SELECT access_dominates(access_label('TOP SECRET'), access_label('SECRET', '{NATO}'))"#,
+        );
+        assert_eq!(val, Ok(Some(false)));
+        let val = Spi::get_one::<String>(
+            r#"SELECT access_lub(access_label('SECRET', '{NATO}'), access_label('CONFIDENTIAL', '{CRYPTO}'))::text"#,
+        );
+        assert_eq!(val, Ok(Some("(SECRET,\"{CRYPTO,NATO}\")".to_string())));
+        let val = Spi::get_one::<String>(
+            r#"SELECT access_glb(access_label('SECRET', '{NATO}'), access_label('CONFIDENTIAL', '{CRYPTO}'))::text"#,
+        );
+        assert_eq!(val, Ok(Some("(CONFIDENTIAL,{})".to_string())));
+        let val = Spi::get_one::<bool>(
+            r#"SELECT access_evaluate(
+                   access_label_expression(access_label('SECRET', '{NATO}')),
+                   access_label_tokens(access_label('TOP SECRET', '{NATO,CRYPTO}')))"#,
+        );
+        assert_eq!(val, Ok(Some(true)));
+    }
+
+    #[pg_test(error = "compartment \"level:SECRET\" may not start with \"level:\"")]
+    fn test_lattice_level_compartment() {
+        Spi::run(r#"INSERT INTO access_levels VALUES ('CONFIDENTIAL', 1), ('SECRET', 2)"#).unwrap();
+        Spi::run(r#"SELECT access_label('CONFIDENTIAL', '{level:SECRET}')"#).unwrap();
+    }
+
+    #[pg_test]
+    fn test_check_write() {
+        Spi::run(r#"SET access.tokens = 'A,B'"#).unwrap();
+        let val = Spi::get_one::<bool>(r#"SELECT access_check_write('A|C')"#);
+        assert_eq!(val, Ok(Some(true)));
+        Spi::run(
+            r#"CREATE TABLE guarded (id int, label accessexpression);
+               CREATE TRIGGER guarded_label BEFORE INSERT OR UPDATE OR DELETE ON guarded
+                   FOR EACH ROW EXECUTE FUNCTION access_check_label('label')"#,
+        )
+        .unwrap();
+        Spi::run(r#"INSERT INTO guarded VALUES (1, 'A&B'), (2, 'A')"#).unwrap();
+        Spi::run(r#"UPDATE guarded SET id = 3 WHERE id = 1"#).unwrap();
+        Spi::run(r#"DELETE FROM guarded WHERE id = 2"#).unwrap();
+        let val = Spi::get_one::<String>(r#"SELECT string_agg(id::text, ',') FROM guarded"#);
+        assert_eq!(val, Ok(Some("3".to_string())));
+        Spi::run(r#"RESET access.tokens"#).unwrap();
+    }
+
+    #[pg_test(
+        error = "existing row's access label is not satisfied by the session's access tokens"
+    )]
+    fn test_check_label_existing_row() {
+        Spi::run(r#"SET access.tokens = 'A,B'"#).unwrap();
+        Spi::run(
+            r#"CREATE TABLE guarded (id int, label accessexpression);
+               CREATE TRIGGER guarded_label BEFORE INSERT OR UPDATE OR DELETE ON guarded
+                   FOR EACH ROW EXECUTE FUNCTION access_check_label('label')"#,
+        )
+        .unwrap();
+        Spi::run(r#"INSERT INTO guarded VALUES (1, 'A&B')"#).unwrap();
+        Spi::run(r#"SET access.tokens = 'A'"#).unwrap();
+        Spi::run(r#"UPDATE guarded SET id = 2"#).unwrap();
+    }
+
+    #[pg_test(error = "new row's access label mentions access tokens the session does not hold")]
+    fn test_check_write_require_held() {
+        Spi::run(r#"SET access.tokens = 'A,B'"#).unwrap();
+        Spi::run(r#"SELECT access_check_write('A|C', require_held => true)"#).unwrap();
+    }
+
+    #[pg_test]
+    fn test_protect_table() {
+        Spi::run(r#"CREATE TABLE secrets (id int, label accessexpression)"#).unwrap();
+        Spi::run(r#"SELECT access_protect_table('secrets', 'label')"#).unwrap();
+        Spi::run(r#"SELECT access_protect_table('secrets', 'label', force => true)"#).unwrap();
+        let val = Spi::get_one::<bool>(
+            r#"SELECT relforcerowsecurity FROM pg_class WHERE oid = 'secrets'::regclass"#,
+        );
+        assert_eq!(val, Ok(Some(true)));
+        Spi::run(r#"SELECT access_protect_table('secrets', 'label', require_held => true)"#)
+            .unwrap();
+        let val = Spi::get_one::<bool>(
+            r#"SELECT relforcerowsecurity FROM pg_class WHERE oid = 'secrets'::regclass"#,
+        );
+        assert_eq!(val, Ok(Some(false)));
+        let val =
+            Spi::get_one::<i64>(r#"SELECT count(*) FROM pg_policies WHERE tablename = 'secrets'"#);
+        assert_eq!(val, Ok(Some(4)));
+        let val = Spi::get_one::<bool>(
+            r#"SELECT require_held FROM access_protected_tables WHERE relation = 'secrets'::regclass"#,
+        );
+        assert_eq!(val, Ok(Some(true)));
+        Spi::run(r#"SELECT access_unprotect_table('secrets')"#).unwrap();
+        Spi::run(r#"SELECT access_unprotect_table('secrets')"#).unwrap();
+        let val = Spi::get_one::<bool>(
+            r#"SELECT relrowsecurity FROM pg_class WHERE oid = 'secrets'::regclass"#,
+        );
+        assert_eq!(val, Ok(Some(false)));
+        let val =
+            Spi::get_one::<i64>(r#"SELECT count(*) FROM pg_policies WHERE tablename = 'secrets'"#);
+        assert_eq!(val, Ok(Some(0)));
+    }
+
+    #[pg_test(error = "column \"id\" of relation mislabelled is not an accessexpression")]
+    fn test_protect_table_wrong_column() {
+        Spi::run(r#"CREATE TABLE mislabelled (id int, label accessexpression)"#).unwrap();
+        Spi::run(r#"SELECT access_protect_table('mislabelled', 'id')"#).unwrap();
+    }
+
+    #[pg_test(error = "policy \"access_select\" for table ledger already exists")]
+    fn test_protect_table_existing_policy() {
+        Spi::run(
+            r#"CREATE TABLE ledger (id int, label accessexpression);
+               CREATE POLICY access_select ON ledger FOR SELECT USING (true)"#,
+        )
+        .unwrap();
+        Spi::run(r#"SELECT access_protect_table('ledger', 'label')"#).unwrap();
+    }
+
+    #[pg_test]
+    fn test_security_labels() {
+        Spi::run(r#"CREATE TABLE payroll (id int, salary int)"#).unwrap();
+        Spi::run(r#"INSERT INTO payroll VALUES (1, 100)"#).unwrap();
+        Spi::run(r#"SECURITY LABEL FOR access ON TABLE payroll IS 'STAFF'"#).unwrap();
+        Spi::run(r#"SECURITY LABEL FOR access ON COLUMN payroll.salary IS 'HR'"#).unwrap();
+        Spi::run(r#"SET access.tokens = 'STAFF'"#).unwrap();
+        let val = Spi::get_one::<i32>(r#"SELECT id FROM payroll"#);
+        assert_eq!(val, Ok(Some(1)));
+        Spi::run(r#"SET access.tokens = 'STAFF,HR'"#).unwrap();
+        let val = Spi::get_one::<i32>(r#"SELECT salary FROM payroll"#);
+        assert_eq!(val, Ok(Some(100)));
+        Spi::run(r#"RESET access.tokens"#).unwrap();
+    }
+
+    #[pg_test(error = "permission denied for column salary of table wages")]
+    fn test_security_labels_denied() {
+        Spi::run(r#"CREATE TABLE wages (id int, salary int)"#).unwrap();
+        // Checked once before any label exists, so the label must undo the cached answer.
+        Spi::run(r#"SELECT salary FROM wages"#).unwrap();
+        Spi::run(r#"SECURITY LABEL FOR access ON COLUMN wages.salary IS 'HR'"#).unwrap();
+        Spi::run(r#"SET access.tokens = 'STAFF'"#).unwrap();
+        Spi::run(r#"SELECT salary FROM wages"#).unwrap();
+    }
+
+    #[pg_test]
+    fn test_unprotected_tables() {
+        Spi::run(r#"CREATE TABLE exposed (id int, label accessexpression)"#).unwrap();
+        let val = Spi::get_one::<bool>(
+            r#"SELECT covered FROM access_protection_report() WHERE relation = 'exposed'::regclass"#,
+        );
+        assert_eq!(val, Ok(Some(false)));
+        Spi::run(r#"SET access.unprotected_tables = 'protect'"#).unwrap();
+        Spi::run(r#"CREATE TABLE filed (id int, label accessexpression)"#).unwrap();
+        let val = Spi::get_one::<bool>(
+            r#"SELECT covered FROM access_protection_report() WHERE relation = 'filed'::regclass"#,
+        );
+        assert_eq!(val, Ok(Some(true)));
+        Spi::run(r#"RESET access.unprotected_tables"#).unwrap();
+    }
+
+    #[pg_test]
+    fn test_unprotected_tables_deferred() {
+        Spi::run(r#"SET access.unprotected_tables = 'error'"#).unwrap();
+        Spi::run(r#"CREATE TABLE pending (id int, label accessexpression)"#).unwrap();
+        Spi::run(r#"SELECT access_protect_table('pending', 'label')"#).unwrap();
+        crate::coverage::check_pending_tables();
+        Spi::run(r#"RESET access.unprotected_tables"#).unwrap();
+    }
+
+    #[pg_test(
+        error = "table unguarded has access labels that no row level security policy enforces"
+    )]
+    fn test_unprotected_tables_rejected() {
+        Spi::run(r#"SET access.unprotected_tables = 'error'"#).unwrap();
+        Spi::run(r#"CREATE TABLE unguarded (id int, label accessexpression)"#).unwrap();
+        Spi::run(r#"SELECT access_protect_table('unguarded', 'label')"#).unwrap();
+        crate::coverage::check_pending_tables();
+        Spi::run(r#"DROP POLICY access_select ON unguarded"#).unwrap();
+        crate::coverage::check_pending_tables();
+    }
+
+    #[pg_test]
+    fn test_token_algebra() {
+        let val = Spi::get_one::<String>(
+            r#"SELECT ('A,"b c"'::accesstokens || 'B,A'::accesstokens)::text"#,
+        );
+        assert_eq!(val, Ok(Some("A,B,\"b c\"".to_string())));
+        let val = Spi::get_one::<String>(r#"SELECT access_tokens_intersect('A,B', 'B,C')::text"#);
+        assert_eq!(val, Ok(Some("B".to_string())));
+        let val = Spi::get_one::<String>(r#"SELECT ('A,B'::accesstokens - 'B')::text"#);
+        assert_eq!(val, Ok(Some("A".to_string())));
+        let val = Spi::get_one::<bool>(r#"SELECT 'A'::accesstokens <@ 'A,B'::accesstokens"#);
+        assert_eq!(val, Ok(Some(true)));
+        let val = Spi::get_one::<bool>(r#"SELECT 'A,B'::accesstokens @> 'A'::accesstokens"#);
+        assert_eq!(val, Ok(Some(true)));
+        let val = Spi::get_one::<bool>(r#"SELECT 'A,B'::accesstokens @> 'A|C'::accessexpression"#);
+        assert_eq!(val, Ok(Some(true)));
+        let val = Spi::get_one::<bool>(r#"SELECT 'A'::accesstokens && 'B'::accesstokens"#);
+        assert_eq!(val, Ok(Some(false)));
+        let val = Spi::get_one::<i32>(r#"SELECT access_tokens_cardinality('A,"b c"')"#);
+        assert_eq!(val, Ok(Some(2)));
+        let val = Spi::get_one::<bool>(r#"SELECT access_tokens_contains('A,"b c"', 'b c')"#);
+        assert_eq!(val, Ok(Some(true)));
+        let val = Spi::get_one::<String>(r#"SELECT access_tokens_add('A', 'b c')::text"#);
+        assert_eq!(val, Ok(Some("A,\"b c\"".to_string())));
+        let val = Spi::get_one::<String>(r#"SELECT access_tokens_remove('A,"b c"', 'b c')::text"#);
+        assert_eq!(val, Ok(Some("A".to_string())));
+    }
+
+    #[pg_test(error = "access tokens may not be empty")]
+    fn test_token_algebra_empty_token() {
+        Spi::run(r#"SELECT access_tokens_add('A', '')"#).unwrap();
+    }
+
+    #[pg_test]
+    fn test_tokens_arrays() {
+        let val = Spi::get_one::<String>(r#"SELECT (ARRAY['b c', 'A', 'A']::accesstokens)::text"#);
+        assert_eq!(val, Ok(Some("A,\"b c\"".to_string())));
+        let val = Spi::get_one::<Vec<String>>(r#"SELECT ('A,"b c"'::accesstokens)::text[]"#);
+        assert_eq!(val, Ok(Some(vec!["A".to_string(), "b c".to_string()])));
+        let val = Spi::get_one::<String>(
+            r#"SELECT string_agg(t, '/' ORDER BY t COLLATE "C") FROM access_tokens_unnest('Z,"q\"t"') t"#,
+        );
+        assert_eq!(val, Ok(Some("Z/q\"t".to_string())));
+    }
+
+    #[pg_test(error = "access tokens may not be null")]
+    fn test_tokens_arrays_null() {
+        Spi::run(r#"SELECT ARRAY['A', NULL]::accesstokens"#).unwrap();
+    }
+
+    #[pg_test]
+    fn test_combine_expressions() {
+        let val = Spi::get_one::<String>(r#"SELECT ('A|B'::accessexpression & 'C&(D|E)')::text"#);
+        assert_eq!(val, Ok(Some("C&(A|B)&(D|E)".to_string())));
+        let val = Spi::get_one::<String>(r#"SELECT ('A&B'::accessexpression & 'C')::text"#);
+        assert_eq!(val, Ok(Some("A&B&C".to_string())));
+        let val = Spi::get_one::<String>(r#"SELECT ('A|B'::accessexpression | 'B|C')::text"#);
+        assert_eq!(val, Ok(Some("A|B|C".to_string())));
+        let val = Spi::get_one::<String>(r#"SELECT ('A&B'::accessexpression | '')::text"#);
+        assert_eq!(val, Ok(Some("".to_string())));
+        let val = Spi::get_one::<String>(r#"SELECT access_and('A', '')::text"#);
+        assert_eq!(val, Ok(Some("A".to_string())));
+    }
+
+    #[pg_test]
+    fn test_label_aggregates() {
+        Spi::run(
+            r#"CREATE TABLE contributions (label accessexpression, tokens accesstokens);
+               INSERT INTO contributions VALUES
+                   ('A|B', 'A,B'), ('C', 'B,C'), ('A|B', 'B'), (NULL, NULL)"#,
+        )
+        .unwrap();
+        let val =
+            Spi::get_one::<String>(r#"SELECT access_and_agg(label)::text FROM contributions"#);
+        assert_eq!(val, Ok(Some("C&(A|B)".to_string())));
+        let val = Spi::get_one::<String>(r#"SELECT access_or_agg(label)::text FROM contributions"#);
+        assert_eq!(val, Ok(Some("A|B|C".to_string())));
+        let val = Spi::get_one::<String>(
+            r#"SELECT access_tokens_union_agg(tokens)::text FROM contributions"#,
+        );
+        assert_eq!(val, Ok(Some("A,B,C".to_string())));
+        let val = Spi::get_one::<String>(
+            r#"SELECT access_tokens_intersect_agg(tokens)::text FROM contributions"#,
+        );
+        assert_eq!(val, Ok(Some("B".to_string())));
+    }
+
+    #[pg_test]
+    fn test_simplify() {
+        let val = Spi::get_one::<String>(
+            r#"SELECT string_agg(access_simplify(e)::text, ';' ORDER BY n)
+               FROM (VALUES (1, 'A|(A&B)'::accessexpression), (2, '(A&B)|(A&B&C)'),
+                            (3, 'A&(A|B)'), (4, '(A|B)&(B|A)&C'), (5, '')) v(n, e)"#,
+        );
+        assert_eq!(val, Ok(Some("A;A&B;A;C&(A|B);".to_string())));
+        let val = Spi::get_one::<bool>(r#"SELECT ('A'::accessexpression & 'A|B') = 'A'"#);
+        assert_eq!(val, Ok(Some(true)));
+    }
+
+    /// An HS256 JSON Web Token with `claims`, signed with `key`.
+    fn signed_assertion(key: &str, claims: &str) -> String {
+        use base64::engine::general_purpose::URL_SAFE_NO_PAD;
+        use base64::Engine;
+        use hmac::{Hmac, Mac};
+        let signed = format!(
+            "{}.{}",
+            URL_SAFE_NO_PAD.encode(r#"{"alg":"HS256","typ":"JWT"}"#),
+            URL_SAFE_NO_PAD.encode(claims)
+        );
+        let mut mac = Hmac::<sha2::Sha256>::new_from_slice(key.as_bytes()).unwrap();
+        mac.update(signed.as_bytes());
+        let signature = URL_SAFE_NO_PAD.encode(mac.finalize().into_bytes());
+        format!("{signed}.{signature}")
+    }
+
+    #[pg_test]
+    fn test_signed_tokens() {
+        Spi::run(r#"SET access.signing_key = 'secret'"#).unwrap();
+        Spi::run(r#"CREATE ROLE service"#).unwrap();
+        Spi::run(r#"SET ROLE service"#).unwrap();
+        let assertion = signed_assertion(
+            "secret",
+            r#"{"sub":"alice","tokens":"B,A","exp":4102444800,"nonce":"n1"}"#,
+        );
+        let val = Spi::get_one_with_args::<String>(
+            r#"SELECT access_set_signed_tokens($1)::text"#,
+            &[assertion.as_str().into()],
+        );
+        assert_eq!(val, Ok(Some("A,B".to_string())));
+        let val = Spi::get_one::<String>(r#"SELECT access_current_subject()"#);
+        assert_eq!(val, Ok(Some("alice".to_string())));
+        Spi::run(r#"RESET ROLE"#).unwrap();
+    }
+
+    #[pg_test(error = "token assertion signature does not match")]
+    fn test_signed_tokens_bad_signature() {
+        Spi::run(r#"SET access.signing_key = 'secret'"#).unwrap();
+        let assertion = signed_assertion(
+            "guessed",
+            r#"{"sub":"mallory","tokens":"A","exp":4102444800,"nonce":"n2"}"#,
+        );
+        Spi::run_with_args(
+            r#"SELECT access_set_signed_tokens($1)"#,
+            &[assertion.as_str().into()],
+        )
+        .unwrap();
+    }
+
+    #[pg_test]
+    fn test_reset_tokens() {
+        Spi::run(r#"SET access.signing_key = 'secret'"#).unwrap();
+        Spi::run(r#"SET access.tokens = 'C'"#).unwrap();
+        let assertion = signed_assertion(
+            "secret",
+            r#"{"sub":"bob","tokens":"A","exp":4102444800,"nonce":"n3"}"#,
+        );
+        Spi::run_with_args(
+            r#"SELECT access_set_signed_tokens($1)"#,
+            &[assertion.as_str().into()],
+        )
+        .unwrap();
+        let val = Spi::get_one::<String>(r#"SELECT access_current_tokens()::text"#);
+        assert_eq!(val, Ok(Some("A".to_string())));
+        Spi::run(r#"SELECT access_reset_tokens()"#).unwrap();
+        Spi::run(r#"SET access.tokens = 'C'"#).unwrap();
+        let val = Spi::get_one::<String>(r#"SELECT access_current_tokens()::text"#);
+        assert_eq!(val, Ok(Some("".to_string())));
+        let val = Spi::get_one::<String>(r#"SELECT access_current_subject()"#);
+        assert_eq!(val, Ok(None));
+        Spi::run(r#"RESET access.signing_key"#).unwrap();
+        let val = Spi::get_one::<String>(r#"SELECT access_current_tokens()::text"#);
+        assert_eq!(val, Ok(Some("C".to_string())));
+        Spi::run(r#"RESET access.tokens"#).unwrap();
+    }
+
+    #[pg_test]
+    fn test_binary_round_trip() {
+        Spi::run(
+            r#"CREATE TABLE sent (e accessexpression, t accesstokens);
+               CREATE TABLE received (LIKE sent);
+               INSERT INTO sent VALUES ('(b&D)|Z|(a|c)', 'Z,"é",A'), ('', '');
+               DO $$
+               DECLARE
+                   path text := current_setting('data_directory') || '/access_round_trip.bin';
+               BEGIN
+                   EXECUTE format('COPY sent TO %L (FORMAT binary)', path);
+                   EXECUTE format('COPY received FROM %L (FORMAT binary)', path);
+               END
+               $$"#,
+        )
+        .unwrap();
+        let val = Spi::get_one::<String>(
+            r#"SELECT string_agg(e::text || '/' || t::text, ';' ORDER BY e::text) FROM received"#,
+        );
+        assert_eq!(val, Ok(Some("/;Z|a|c|(D&b)/A,Z,\"é\"".to_string())));
+        let val = Spi::get_one::<Vec<u8>>(r#"SELECT accesstokens_send('Z,"é",A')"#);
+        assert_eq!(
+            val,
+            Ok(Some(vec![
+                1, 0, 0, 0, 3, 0, 0, 0, 1, b'A', 0, 0, 0, 1, b'Z', 0, 0, 0, 2, 0xc3, 0xa9
+            ]))
+        );
     }
 }
@@ -0,0 +1,281 @@
+/*
+  Copyright 2025 Will Murnane
+
+  Licensed under the Apache License, Version 2.0 (the "License");
+  you may not use this file except in compliance with the License.
+  You may obtain a copy of the License at
+
+      http://www.apache.org/licenses/LICENSE-2.0
+
+  Unless required by applicable law or agreed to in writing, software
+  distributed under the License is distributed on an "AS IS" BASIS,
+  WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+  See the License for the specific language governing permissions and
+  limitations under the License.
+*/
+
+//! Planner support for `access_evaluate`, and restriction estimators for the `<@` and `@>`
+//! operators between labels and tokens.
+//!
+//! - `SupportRequestSelectivity`: when one argument is a column and the other can be reduced to a
+//!   constant (stable calls such as `get_current_user_tokens()` are evaluated for the estimate,
+//!   the same way the planner treats them elsewhere), the constant is evaluated against the
+//!   column's most common values and histogram, as `generic_restriction_selectivity` does for
+//!   operators. The one-argument `access_evaluate` is estimated with the tokens the session holds
+//!   while planning.
+//! - `SupportRequestCost`: one `cpu_operator_cost` per instruction of the expression's program,
+//!   plus one for the call.
+//!
+//! There is no `SupportRequestSimplify` handling: the function is immutable, so a call with
+//! constant arguments is evaluated by the planner before it would ask.
+
+use crate::guc;
+use crate::storage::{StoredExpression, StoredTokens};
+use pgrx::prelude::*;
+use pgrx::{is_a, FromDatum, Internal, PgList};
+
+/// Selectivity among values not covered by the most common values when there is no histogram.
+const DEFAULT_SELECTIVITY: f64 = 0.333;
+/// Assumed program length when the expression isn't a constant: that of a label of five tokens
+/// and three junctions, one instruction each, such as `(A|B|C)&(D|E)`.
+const DEFAULT_INSTRUCTIONS: f64 = 8.0;
+
+#[pg_extern(immutable, parallel_safe, strict)]
+pub(crate) fn access_evaluate_support(request: Internal) -> Internal {
+    let Some(request) = request.unwrap() else {
+        return Internal::from(None);
+    };
+    let request = request.cast_mut_ptr::<pg_sys::Node>();
+    // SAFETY: the planner only calls support functions with a SupportRequest* node, whose tag says
+    // which one it is.
+    let response = unsafe {
+        if is_a(request, pg_sys::NodeTag::T_SupportRequestSelectivity) {
+            selectivity(request.cast())
+        } else if is_a(request, pg_sys::NodeTag::T_SupportRequestCost) {
+            cost(request.cast())
+        } else {
+            std::ptr::null_mut()
+        }
+    };
+    Internal::from((!response.is_null()).then(|| pg_sys::Datum::from(response)))
+}
+
+/// `node` as a `Const`, if it is one.
+unsafe fn constant<'a>(node: *mut pg_sys::Node) -> Option<&'a pg_sys::Const> {
+    // SAFETY: a node tagged T_Const is a Const.
+    unsafe { (!node.is_null() && is_a(node, pg_sys::NodeTag::T_Const)).then(|| &*node.cast()) }
+}
+
+unsafe fn selectivity(request: *mut pg_sys::SupportRequestSelectivity) -> *mut pg_sys::Node {
+    let request = unsafe { &mut *request };
+    if request.is_join {
+        return std::ptr::null_mut();
+    }
+    let args = unsafe { PgList::<pg_sys::Node>::from_pg(request.args) };
+    let Some(left) = args.get_ptr(0) else {
+        return std::ptr::null_mut();
+    };
+    let (root, var_relid) = (request.root, request.varRelid);
+    // SAFETY: root and the arguments come straight from the planner.
+    let estimate = unsafe {
+        match args.get_ptr(1) {
+            Some(right) => satisfied_selectivity(root, left, right, var_relid),
+            None => {
+                let tokens = guc::with_current_tokens(StoredTokens::clone);
+                column_selectivity(root, left, var_relid, |value| {
+                    StoredExpression::from_datum(value, false)
+                        .is_some_and(|expression| expression.evaluate(&tokens))
+                })
+            }
+        }
+    };
+    match estimate {
+        Some(selectivity) => {
+            request.selectivity = selectivity;
+            (request as *mut pg_sys::SupportRequestSelectivity).cast()
+        }
+        None => std::ptr::null_mut(),
+    }
+}
+
+/// Estimate the fraction of rows in which the tokens `tokens` satisfy the expression `expression`,
+/// when one of them is a column and the other reduces to a constant. `None` if neither does, or
+/// there are no statistics to go on.
+unsafe fn satisfied_selectivity(
+    root: *mut pg_sys::PlannerInfo,
+    expression: *mut pg_sys::Node,
+    tokens: *mut pg_sys::Node,
+    var_relid: i32,
+) -> Option<f64> {
+    // SAFETY: the folded values are plain expression trees, and the constants are of the types the
+    // arguments have.
+    unsafe {
+        let expression_value = pg_sys::estimate_expression_value(root, expression);
+        let tokens_value = pg_sys::estimate_expression_value(root, tokens);
+        if let Some(held) = constant(tokens_value) {
+            match StoredTokens::from_datum(held.constvalue, held.constisnull) {
+                None => Some(0.0),
+                Some(held) => column_selectivity(root, expression, var_relid, |value| {
+                    StoredExpression::from_datum(value, false)
+                        .is_some_and(|expression| expression.evaluate(&held))
+                }),
+            }
+        } else if let Some(label) = constant(expression_value) {
+            match StoredExpression::from_datum(label.constvalue, label.constisnull) {
+                None => Some(0.0),
+                Some(label) => column_selectivity(root, tokens, var_relid, |value| {
+                    StoredTokens::from_datum(value, false)
+                        .is_some_and(|tokens| label.evaluate(&tokens))
+                }),
+            }
+        } else {
+            None
+        }
+    }
+}
+
+/// The selectivity of a `<@` or `@>` restriction clause, whose arguments are the expression and
+/// the tokens in the order `expression_first` says.
+fn operator_selectivity(
+    root: Internal,
+    args: Internal,
+    var_relid: i32,
+    expression_first: bool,
+) -> f64 {
+    let (Some(root), Some(args)) = (root.unwrap(), args.unwrap()) else {
+        return DEFAULT_SELECTIVITY;
+    };
+    // SAFETY: the planner calls restriction estimators with its PlannerInfo and the clause's
+    // argument list.
+    unsafe {
+        let args = PgList::<pg_sys::Node>::from_pg(args.cast_mut_ptr());
+        let (Some(first), Some(second)) = (args.get_ptr(0), args.get_ptr(1)) else {
+            return DEFAULT_SELECTIVITY;
+        };
+        let (expression, tokens) = if expression_first {
+            (first, second)
+        } else {
+            (second, first)
+        };
+        satisfied_selectivity(root.cast_mut_ptr(), expression, tokens, var_relid)
+            .unwrap_or(DEFAULT_SELECTIVITY)
+    }
+}
+
+/// Restriction estimator for `expression <@ tokens`.
+#[pg_extern(stable, parallel_safe, strict)]
+pub(crate) fn accessexpression_satisfied_by_sel(
+    root: Internal,
+    _operator: pg_sys::Oid,
+    args: Internal,
+    var_relid: i32,
+) -> f64 {
+    operator_selectivity(root, args, var_relid, true)
+}
+
+/// Restriction estimator for `tokens @> expression`.
+#[pg_extern(stable, parallel_safe, strict)]
+pub(crate) fn accesstokens_satisfies_sel(
+    root: Internal,
+    _operator: pg_sys::Oid,
+    args: Internal,
+    var_relid: i32,
+) -> f64 {
+    operator_selectivity(root, args, var_relid, false)
+}
+
+/// Estimate the fraction of rows for which `satisfies` holds on the column (or expression) `node`,
+/// from its statistics. `None` if there are no statistics to go on.
+unsafe fn column_selectivity(
+    root: *mut pg_sys::PlannerInfo,
+    node: *mut pg_sys::Node,
+    var_relid: i32,
+    satisfies: impl Fn(pg_sys::Datum) -> bool,
+) -> Option<f64> {
+    // SAFETY: examine_variable fills in vardata, whose stats tuple (if any) is a pg_statistic row
+    // that stays valid until it is released below.
+    unsafe {
+        let mut vardata: pg_sys::VariableStatData = std::mem::zeroed();
+        pg_sys::examine_variable(root, node, var_relid, &mut vardata);
+        let tuple = vardata.statsTuple;
+        if tuple.is_null() {
+            return None;
+        }
+        let stats = pg_sys::heap_tuple_get_struct::<pg_sys::FormData_pg_statistic>(tuple);
+        let null_fraction = (*stats).stanullfrac as f64;
+
+        let mut sslot: pg_sys::AttStatsSlot = std::mem::zeroed();
+        let (mut mcv_selected, mut mcv_total) = (0.0, 0.0);
+        if pg_sys::get_attstatsslot(
+            &mut sslot,
+            tuple,
+            pg_sys::STATISTIC_KIND_MCV as i32,
+            pg_sys::InvalidOid,
+            (pg_sys::ATTSTATSSLOT_VALUES | pg_sys::ATTSTATSSLOT_NUMBERS) as i32,
+        ) {
+            for i in 0..sslot.nvalues as usize {
+                let frequency = *sslot.numbers.add(i) as f64;
+                mcv_total += frequency;
+                if satisfies(*sslot.values.add(i)) {
+                    mcv_selected += frequency;
+                }
+            }
+            pg_sys::free_attstatsslot(&mut sslot);
+        }
+
+        let mut other_selectivity = None;
+        if pg_sys::get_attstatsslot(
+            &mut sslot,
+            tuple,
+            pg_sys::STATISTIC_KIND_HISTOGRAM as i32,
+            pg_sys::InvalidOid,
+            pg_sys::ATTSTATSSLOT_VALUES as i32,
+        ) {
+            let n = sslot.nvalues as usize;
+            if n > 0 {
+                let hits = (0..n).filter(|&i| satisfies(*sslot.values.add(i))).count();
+                other_selectivity = Some(hits as f64 / n as f64);
+            }
+            pg_sys::free_attstatsslot(&mut sslot);
+        }
+        if let Some(free) = vardata.freefunc {
+            free(tuple);
+        }
+
+        let other_selectivity = other_selectivity.unwrap_or(if mcv_total > 0.0 {
+            mcv_selected / mcv_total
+        } else {
+            DEFAULT_SELECTIVITY
+        });
+        let other_fraction = (1.0 - null_fraction - mcv_total).max(0.0);
+        Some((mcv_selected + other_selectivity * other_fraction).clamp(0.0, 1.0))
+    }
+}
+
+unsafe fn cost(request: *mut pg_sys::SupportRequestCost) -> *mut pg_sys::Node {
+    let request = unsafe { &mut *request };
+    let instructions = unsafe { instructions(request.node) };
+    request.startup = 0.0;
+    // SAFETY: cpu_operator_cost is a GUC variable, only ever written by the backend's own thread.
+    request.per_tuple = unsafe { pg_sys::cpu_operator_cost } * (1.0 + instructions);
+    (request as *mut pg_sys::SupportRequestCost).cast()
+}
+
+/// The program length of the expression `access_evaluate` is called on: exact for a constant,
+/// otherwise [`DEFAULT_INSTRUCTIONS`].
+unsafe fn instructions(node: *mut pg_sys::Node) -> f64 {
+    // SAFETY: node, when set, is the call being costed.
+    unsafe {
+        if node.is_null() || !is_a(node, pg_sys::NodeTag::T_FuncExpr) {
+            return DEFAULT_INSTRUCTIONS;
+        }
+        let args = PgList::<pg_sys::Node>::from_pg((*node.cast::<pg_sys::FuncExpr>()).args);
+        match args.get_ptr(0).and_then(|expression| constant(expression)) {
+            Some(expression) => {
+                StoredExpression::from_datum(expression.constvalue, expression.constisnull)
+                    .map_or(0.0, |expression| expression.instructions() as f64)
+            }
+            None => DEFAULT_INSTRUCTIONS,
+        }
+    }
+}
@@ -0,0 +1,133 @@
+/*
+  Copyright 2025 Will Murnane
+
+  Licensed under the Apache License, Version 2.0 (the "License");
+  you may not use this file except in compliance with the License.
+  You may obtain a copy of the License at
+
+      http://www.apache.org/licenses/LICENSE-2.0
+
+  Unless required by applicable law or agreed to in writing, software
+  distributed under the License is distributed on an "AS IS" BASIS,
+  WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+  See the License for the specific language governing permissions and
+  limitations under the License.
+*/
+
+//! The `access_principals` registry of each role's tokens, and a cached lookup into it.
+//!
+//! Each backend remembers the tokens it has looked up, and drops them all when the table changes
+//! (see `catalog::access_catalog_changed`) or when roles are dropped or renamed. Between changes,
+//! a lookup is a hash table probe.
+
+use crate::storage::StoredTokens;
+use crate::{catalog, AccessTokens};
+use pgrx::pg_sys::panic::ErrorReport;
+use pgrx::prelude::*;
+use pgrx::{function_name, PgLogLevel, PgSqlErrorCode};
+use std::cell::{Cell, RefCell};
+use std::collections::HashMap;
+
+thread_local! {
+    static CACHE: RefCell<HashMap<pg_sys::Oid, StoredTokens>> = RefCell::new(HashMap::new());
+    /// The table's OID, once a lookup has found it.
+    static TABLE: Cell<pg_sys::Oid> = const { Cell::new(pg_sys::InvalidOid) };
+}
+
+pub(crate) fn init() {
+    // SAFETY: called once per backend from _PG_init; the callbacks live as long as the library.
+    unsafe {
+        pg_sys::CacheRegisterRelcacheCallback(Some(relcache_invalidated), pg_sys::Datum::from(0));
+        pg_sys::CacheRegisterSyscacheCallback(
+            pg_sys::SysCacheIdentifier::AUTHOID as i32,
+            Some(roles_invalidated),
+            pg_sys::Datum::from(0),
+        );
+    }
+}
+
+#[pg_guard]
+unsafe extern "C-unwind" fn relcache_invalidated(_arg: pg_sys::Datum, relid: pg_sys::Oid) {
+    // An invalid relid means every relation was invalidated.
+    if relid == pg_sys::InvalidOid || relid == TABLE.get() {
+        CACHE.with_borrow_mut(HashMap::clear);
+    }
+}
+
+#[pg_guard]
+unsafe extern "C-unwind" fn roles_invalidated(_arg: pg_sys::Datum, _cache: i32, _hash: u32) {
+    CACHE.with_borrow_mut(HashMap::clear);
+}
+
+/// The tokens registered for `role`, empty if it has none.
+pub(crate) fn registered_tokens(role: pg_sys::Oid) -> StoredTokens {
+    if let Some(tokens) = CACHE.with_borrow(|cache| cache.get(&role).cloned()) {
+        return tokens;
+    }
+    let table = catalog::qualified("access_principals");
+    let (oid, text) = catalog::as_superuser(|| {
+        let oid = catalog::table_oid("access_principals");
+        let text = Spi::get_one_with_args::<String>(
+            &format!(
+                "SELECT tokens::pg_catalog.text FROM {table} WHERE role OPERATOR(pg_catalog.=) $1::pg_catalog.regrole"
+            ),
+            &[role.into()],
+        );
+        (oid, text)
+    });
+    if let Some(oid) = oid {
+        TABLE.set(oid);
+    }
+    let tokens = match text.ok().flatten() {
+        Some(text) => StoredTokens::from(&AccessTokens(
+            ::access::tokens(&text)
+                .unwrap_or_else(|_| panic!("stored tokens \"{text}\" failed to parse")),
+        )),
+        None => StoredTokens::default(),
+    };
+    CACHE.with_borrow_mut(|cache| cache.insert(role, tokens.clone()));
+    tokens
+}
+
+/// The tokens registered in `access_principals` for `role`, by default the current role; empty
+/// if it has none. Only superusers and roles with the privileges of `role` may ask about it.
+#[pg_extern(stable, parallel_safe)]
+fn access_principal_tokens(role: default!(Option<catalog::Regrole>, "NULL")) -> StoredTokens {
+    let current = catalog::current_role();
+    let role = role.map_or(current, |role| role.0);
+    // SAFETY: has_privs_of_role only reads the role catalogs; it is true for superusers.
+    if role != current && !unsafe { pg_sys::has_privs_of_role(current, role) } {
+        ErrorReport::new(
+            PgSqlErrorCode::ERRCODE_INSUFFICIENT_PRIVILEGE,
+            format!(
+                "permission denied to read the access tokens of role \"{}\"",
+                catalog::role_name(role)
+            ),
+            function_name!(),
+        )
+        .set_detail("Only roles with the privileges of that role may read its tokens.")
+        .report(PgLogLevel::ERROR);
+        unreachable!("ERROR-level reports do not return")
+    }
+    registered_tokens(role)
+}
+
+extension_sql!(
+    r#"
+CREATE TABLE access_principals (
+    role regrole PRIMARY KEY,
+    tokens accesstokens NOT NULL
+);
+REVOKE ALL ON access_principals FROM PUBLIC;
+SELECT pg_catalog.pg_extension_config_dump('access_principals', '');
+
+CREATE TRIGGER access_principals_changed
+    AFTER INSERT OR UPDATE OR DELETE OR TRUNCATE ON access_principals
+    FOR EACH STATEMENT EXECUTE FUNCTION access_catalog_changed();
+
+COMMENT ON TABLE access_principals IS
+    'The access tokens each role holds, as returned by access_principal_tokens()';
+"#,
+    name = "access_principals",
+    requires = ["accesstokens", catalog::access_catalog_changed]
+);
@@ -0,0 +1,295 @@
+/*
+  Copyright 2025 Will Murnane
+
+  Licensed under the Apache License, Version 2.0 (the "License");
+  you may not use this file except in compliance with the License.
+  You may obtain a copy of the License at
+
+      http://www.apache.org/licenses/LICENSE-2.0
+
+  Unless required by applicable law or agreed to in writing, software
+  distributed under the License is distributed on an "AS IS" BASIS,
+  WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+  See the License for the specific language governing permissions and
+  limitations under the License.
+*/
+
+//! Installing and removing label-based row level security on a table in one call.
+//!
+//! `access_protect_table()` enables row level security and creates one policy per command, all
+//! reading the session's tokens from `access_current_tokens()`: rows are visible, updatable and
+//! deletable when their label is satisfied (using `<@`, so a GIN index on the label can help), and
+//! new labels must pass `access_check_write()`. What it did is recorded in
+//! `access_protected_tables`, so calling it again replaces the policies with current ones, and
+//! `access_unprotect_table()` can put the table back the way it was.
+//!
+//! The DDL runs as the caller, so only the table's owner can protect it; only the bookkeeping in
+//! `access_protected_tables` is done as a superuser. Policies with the names it uses on a table it
+//! didn't protect are someone else's, and are an error rather than silently replaced.
+
+use crate::{catalog, coverage};
+use pgrx::pg_sys::panic::ErrorReport;
+use pgrx::prelude::*;
+use pgrx::spi::quote_identifier;
+use pgrx::{function_name, PgLogLevel, PgSqlErrorCode};
+use std::cell::Cell;
+
+/// The policies `access_protect_table` creates, and the command each applies to.
+const POLICIES: [(&str, &str); 4] = [
+    ("access_select", "SELECT"),
+    ("access_insert", "INSERT"),
+    ("access_update", "UPDATE"),
+    ("access_delete", "DELETE"),
+];
+
+thread_local! {
+    /// Set while `access_protect_table`, or the DDL of `access_unprotect_table`, runs, so it
+    /// isn't checked for coverage piecemeal.
+    static PROTECTING: Cell<bool> = const { Cell::new(false) };
+}
+
+/// Whether `access_protect_table` or `access_unprotect_table` is running DDL in this backend.
+pub(crate) fn protecting() -> bool {
+    PROTECTING.get()
+}
+
+/// Marks one of them as running DDL until dropped, even if it errors out.
+struct Protecting(bool);
+
+impl Protecting {
+    fn start() -> Self {
+        Protecting(PROTECTING.replace(true))
+    }
+}
+
+impl Drop for Protecting {
+    fn drop(&mut self) {
+        PROTECTING.set(self.0);
+    }
+}
+
+fn error(code: PgSqlErrorCode, message: String) -> ! {
+    ErrorReport::new(code, message, function_name!()).report(PgLogLevel::ERROR);
+    unreachable!("ERROR-level reports do not return")
+}
+
+fn run(sql: &str) {
+    Spi::run(sql).unwrap_or_else(|e| panic!("failed to run \"{sql}\": {e}"));
+}
+
+/// The name of the table `relation`, quoted for use in SQL.
+fn table_name(relation: pg_sys::Oid) -> String {
+    Spi::get_one_with_args::<String>(
+        "SELECT $1::pg_catalog.regclass::pg_catalog.text",
+        &[relation.into()],
+    )
+    .ok()
+    .flatten()
+    .expect("a regclass argument names a table")
+}
+
+/// How row level security was on `relation` before it was first protected, if it is protected.
+fn recorded(relation: pg_sys::Oid) -> Option<(bool, bool)> {
+    catalog::as_superuser(|| {
+        Spi::get_two_with_args::<bool, bool>(
+            &format!(
+                "SELECT rls_was_enabled, rls_was_forced FROM {} WHERE relation OPERATOR(pg_catalog.=) $1",
+                catalog::qualified("access_protected_tables")
+            ),
+            &[relation.into()],
+        )
+    })
+    .ok()
+    .and_then(|(enabled, forced)| enabled.zip(forced))
+}
+
+/// Protect `relation` by the labels in its `accessexpression` column `label_column`. With
+/// `require_held`, writes may only use labels mentioning tokens the writer holds; with `force`,
+/// the table's owner is subject to the policies too; with `grant_to`, that role is granted
+/// `SELECT`, `INSERT`, `UPDATE` and `DELETE` on the table.
+#[pg_extern(requires = ["access_protected_tables"])]
+pub(crate) fn access_protect_table(
+    relation: catalog::Regclass,
+    label_column: &str,
+    require_held: default!(bool, false),
+    force: default!(bool, false),
+    grant_to: default!(Option<catalog::Regrole>, "NULL"),
+) {
+    let oid = relation.0;
+    let table = table_name(oid);
+    let label_type = Spi::get_one_with_args::<bool>(
+        &format!(
+            "SELECT atttypid OPERATOR(pg_catalog.=) '{}'::pg_catalog.regtype FROM pg_catalog.pg_attribute
+             WHERE attrelid OPERATOR(pg_catalog.=) $1 AND attname OPERATOR(pg_catalog.=) $2
+               AND attnum OPERATOR(pg_catalog.>) 0 AND NOT attisdropped",
+            catalog::qualified("accessexpression")
+        ),
+        &[oid.into(), label_column.into()],
+    )
+    .ok()
+    .flatten();
+    match label_type {
+        None => error(
+            PgSqlErrorCode::ERRCODE_UNDEFINED_COLUMN,
+            format!("column \"{label_column}\" of relation {table} does not exist"),
+        ),
+        Some(false) => error(
+            PgSqlErrorCode::ERRCODE_DATATYPE_MISMATCH,
+            format!("column \"{label_column}\" of relation {table} is not an accessexpression"),
+        ),
+        Some(true) => {}
+    }
+    // Protecting the table again keeps what was recorded the first time, since by now row level
+    // security may be on (and forced) only because of that.
+    let (was_enabled, was_forced) = recorded(oid).unwrap_or_else(|| {
+        let clashing = Spi::get_one_with_args::<String>(
+            "SELECT polname::pg_catalog.text FROM pg_catalog.pg_policy
+             WHERE polrelid OPERATOR(pg_catalog.=) $1 AND polname OPERATOR(pg_catalog.=) ANY ($2)
+             ORDER BY polname LIMIT 1",
+            &[
+                oid.into(),
+                POLICIES.map(|(policy, _)| policy).to_vec().into(),
+            ],
+        )
+        .ok()
+        .flatten();
+        if let Some(policy) = clashing {
+            ErrorReport::new(
+                PgSqlErrorCode::ERRCODE_DUPLICATE_OBJECT,
+                format!("policy \"{policy}\" for table {table} already exists"),
+                function_name!(),
+            )
+            .set_detail("The table is not in access_protected_tables, so the policy isn't one access_protect_table() made.")
+            .set_hint("Rename or drop the policy first.")
+            .report(PgLogLevel::ERROR);
+        }
+        Spi::get_two_with_args::<bool, bool>(
+            "SELECT relrowsecurity, relforcerowsecurity FROM pg_catalog.pg_class WHERE oid OPERATOR(pg_catalog.=) $1",
+            &[oid.into()],
+        )
+        .ok()
+        .and_then(|(enabled, forced)| enabled.zip(forced))
+        .expect("a regclass argument names a table")
+    });
+
+    let _protecting = Protecting::start();
+    let column = quote_identifier(label_column);
+    let readable = format!(
+        "{column} OPERATOR({}.<@) {}()",
+        catalog::schema(),
+        catalog::qualified("access_current_tokens")
+    );
+    let writable = format!(
+        "{}({column}, {require_held})",
+        catalog::qualified("access_check_write")
+    );
+    run(&format!("ALTER TABLE {table} ENABLE ROW LEVEL SECURITY"));
+    if force {
+        run(&format!("ALTER TABLE {table} FORCE ROW LEVEL SECURITY"));
+    } else if !was_forced {
+        run(&format!("ALTER TABLE {table} NO FORCE ROW LEVEL SECURITY"));
+    }
+    for (policy, command) in POLICIES {
+        run(&format!("DROP POLICY IF EXISTS {policy} ON {table}"));
+        let clauses = match command {
+            "SELECT" | "DELETE" => format!("USING ({readable})"),
+            "INSERT" => format!("WITH CHECK ({writable})"),
+            _ => format!("USING ({readable}) WITH CHECK ({writable})"),
+        };
+        run(&format!(
+            "CREATE POLICY {policy} ON {table} FOR {command} {clauses}"
+        ));
+    }
+    if let Some(role) = grant_to {
+        run(&format!(
+            "GRANT SELECT, INSERT, UPDATE, DELETE ON {table} TO {}",
+            quote_identifier(&catalog::role_name(role.0))
+        ));
+    }
+
+    let query = format!(
+        "INSERT INTO {} (relation, label_column, require_held, forced, rls_was_enabled, rls_was_forced, protected_by)
+         VALUES ($1, $2, $3, $4, $5, $6, $7)
+         ON CONFLICT (relation) DO UPDATE SET label_column = excluded.label_column,
+             require_held = excluded.require_held, forced = excluded.forced,
+             protected_by = excluded.protected_by, protected_at = pg_catalog.now()",
+        catalog::qualified("access_protected_tables")
+    );
+    let protected_by = catalog::current_role();
+    catalog::as_superuser(|| {
+        Spi::run_with_args(
+            &query,
+            &[
+                oid.into(),
+                label_column.into(),
+                require_held.into(),
+                force.into(),
+                was_enabled.into(),
+                was_forced.into(),
+                protected_by.into(),
+            ],
+        )
+    })
+    .unwrap_or_else(|e| panic!("failed to record protection of {table}: {e}"));
+}
+
+/// Remove what `access_protect_table` did to `relation`: drop its policies and restore row level
+/// security to how it was. Grants are left in place. Does nothing, with a notice, if the table
+/// isn't protected.
+#[pg_extern(requires = ["access_protected_tables"])]
+fn access_unprotect_table(relation: catalog::Regclass) {
+    let oid = relation.0;
+    let table = table_name(oid);
+    let Some((was_enabled, was_forced)) = recorded(oid) else {
+        pgrx::notice!("relation {table} is not protected, skipping");
+        return;
+    };
+    {
+        let _protecting = Protecting::start();
+        for (policy, _) in POLICIES {
+            run(&format!("DROP POLICY IF EXISTS {policy} ON {table}"));
+        }
+        if !was_forced {
+            run(&format!("ALTER TABLE {table} NO FORCE ROW LEVEL SECURITY"));
+        }
+        if !was_enabled {
+            run(&format!("ALTER TABLE {table} DISABLE ROW LEVEL SECURITY"));
+        }
+    }
+    catalog::as_superuser(|| {
+        Spi::run_with_args(
+            &format!(
+                "DELETE FROM {} WHERE relation OPERATOR(pg_catalog.=) $1",
+                catalog::qualified("access_protected_tables")
+            ),
+            &[oid.into()],
+        )
+    })
+    .unwrap_or_else(|e| panic!("failed to forget protection of {table}: {e}"));
+    // Its DDL was left alone while it ran, so each dropped policy didn't count separately; the
+    // table as it is now is checked once instead.
+    coverage::access_check_table_protection(oid);
+}
+
+extension_sql!(
+    r#"
+CREATE TABLE access_protected_tables (
+    relation regclass PRIMARY KEY,
+    label_column name NOT NULL,
+    require_held boolean NOT NULL,
+    forced boolean NOT NULL,
+    rls_was_enabled boolean NOT NULL,
+    rls_was_forced boolean NOT NULL,
+    protected_by regrole NOT NULL,
+    protected_at timestamptz NOT NULL DEFAULT now()
+);
+REVOKE ALL ON access_protected_tables FROM PUBLIC;
+GRANT SELECT ON access_protected_tables TO PUBLIC;
+SELECT pg_catalog.pg_extension_config_dump('access_protected_tables', '');
+
+COMMENT ON TABLE access_protected_tables IS
+    'Tables given label-based row level security by access_protect_table(), and how they were before';
+"#,
+    name = "access_protected_tables",
+    requires = ["accessexpression"]
+);
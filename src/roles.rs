@@ -0,0 +1,73 @@
+/*
+  Copyright 2025 Will Murnane
+
+  Licensed under the Apache License, Version 2.0 (the "License");
+  you may not use this file except in compliance with the License.
+  You may obtain a copy of the License at
+
+      http://www.apache.org/licenses/LICENSE-2.0
+
+  Unless required by applicable law or agreed to in writing, software
+  distributed under the License is distributed on an "AS IS" BASIS,
+  WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+  See the License for the specific language governing permissions and
+  limitations under the License.
+*/
+
+//! Tokens named after the roles the current role inherits the privileges of.
+//!
+//! Membership is followed through `pg_auth_members` rather than with `pg_has_role()`, which
+//! reports every role as held by a superuser. A grant is only followed if it passes on
+//! privileges: on PostgreSQL 16 and later that is the grant's own `INHERIT` option, and before
+//! that it is the member's `rolinherit`.
+
+use crate::storage::StoredTokens;
+use crate::{catalog, AccessTokens};
+use pgrx::prelude::*;
+
+#[cfg(any(feature = "pg16", feature = "pg17", feature = "pg18"))]
+const INHERITS: &str = "m.inherit_option";
+
+#[cfg(not(any(feature = "pg16", feature = "pg17", feature = "pg18")))]
+const INHERITS: &str =
+    "(SELECT r.rolinherit FROM pg_catalog.pg_roles r WHERE r.oid OPERATOR(pg_catalog.=) m.member)";
+
+/// Tokens named after every role the current role is a member of, directly or indirectly, and
+/// inherits the privileges of. With a `prefix`, only roles whose names start with it count, and
+/// unless `strip_prefix` is false it is removed from the tokens.
+#[pg_extern(stable, parallel_safe)]
+fn access_tokens_from_roles(
+    prefix: default!(Option<&str>, "NULL"),
+    strip_prefix: default!(bool, true),
+) -> StoredTokens {
+    let query = format!(
+        "WITH RECURSIVE held(oid) AS (
+             SELECT $1::pg_catalog.oid
+             UNION
+             SELECT m.roleid FROM pg_catalog.pg_auth_members m JOIN held h ON m.member OPERATOR(pg_catalog.=) h.oid
+             WHERE {INHERITS}
+         )
+         SELECT r.rolname::pg_catalog.text FROM pg_catalog.pg_roles r JOIN held h ON r.oid OPERATOR(pg_catalog.=) h.oid
+         WHERE r.oid OPERATOR(pg_catalog.<>) $1"
+    );
+    let role = catalog::current_role();
+    let names: Vec<String> = Spi::connect(|client| {
+        client
+            .select(&query, None, &[role.into()])?
+            .filter_map(|row| row.get::<String>(1).transpose())
+            .collect::<Result<_, _>>()
+    })
+    .unwrap_or_else(|e| panic!("failed to read role memberships: {e}"));
+    let tokens: Vec<&str> = names
+        .iter()
+        .filter_map(|name| match prefix {
+            None => Some(name.as_str()),
+            Some(prefix) if strip_prefix => name.strip_prefix(prefix),
+            Some(prefix) => name.starts_with(prefix).then_some(name.as_str()),
+        })
+        .filter(|token| !token.is_empty())
+        .collect();
+    let tokens = AccessTokens::from_values(&tokens)
+        .unwrap_or_else(|e| panic!("role names {tokens:?} did not make valid tokens: {e:?}"));
+    StoredTokens::from(&tokens)
+}
@@ -0,0 +1,347 @@
+/*
+  Copyright 2025 Will Murnane
+
+  Licensed under the Apache License, Version 2.0 (the "License");
+  you may not use this file except in compliance with the License.
+  You may obtain a copy of the License at
+
+      http://www.apache.org/licenses/LICENSE-2.0
+
+  Unless required by applicable law or agreed to in writing, software
+  distributed under the License is distributed on an "AS IS" BASIS,
+  WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+  See the License for the specific language governing permissions and
+  limitations under the License.
+*/
+
+//! The `access` security label provider: access expressions on tables and columns.
+//!
+//! `SECURITY LABEL FOR access ON TABLE t IS 'AUDITOR&C_SUITE'` (or `ON COLUMN t.c`) attaches an
+//! expression, which is checked like any `accessexpression` input when it is set. From then on, a
+//! query touching the table, or the column, fails with a permission error unless the session's
+//! tokens (see `access_current_tokens()`) satisfy the label:
+//!
+//! - the executor permission hook checks every relation a query reads or writes, and each column
+//!   it uses (a whole-row reference uses them all);
+//! - the object access hook checks labelled tables and columns before they are dropped.
+//!
+//! Labels are read from `pg_seclabel` as they are needed, as `sepgsql` does. The hooks run for
+//! every query in every database, so each backend first remembers whether its database has any
+//! `access` labels at all, and skips the lookups if not. Setting or removing a label invalidates
+//! the labelled table's relcache entry, which makes every backend look again.
+//!
+//! Labels can only be set where the extension is installed, since checking them needs its
+//! catalog. A label left behind in a database without it makes the queries it covers fail rather
+//! than go unchecked.
+//!
+//! The hooks only exist in backends that have loaded the library, so a backend that loads it
+//! on first use would run queries unchecked until then. The provider is therefore only registered
+//! when the library is in `shared_preload_libraries`; otherwise `SECURITY LABEL FOR access` fails
+//! because no such provider is loaded, and loading the library logs why.
+
+use crate::storage::StoredExpression;
+use crate::{catalog, guc, syntax, AccessExpression};
+use pgrx::pg_sys::panic::ErrorReport;
+use pgrx::prelude::*;
+use pgrx::{function_name, PgList, PgLogLevel, PgSqlErrorCode};
+use std::cell::Cell;
+use std::ffi::{c_char, c_void, CStr};
+
+const PROVIDER: &CStr = c"access";
+
+static mut PREVIOUS_EXECUTOR_CHECK_PERMS: pg_sys::ExecutorCheckPerms_hook_type = None;
+static mut PREVIOUS_OBJECT_ACCESS: pg_sys::object_access_hook_type = None;
+
+thread_local! {
+    /// Whether the current database has any `access` labels, or `None` if not known since the
+    /// last relcache invalidation.
+    static LABELLED: Cell<Option<bool>> = const { Cell::new(None) };
+}
+
+pub(crate) fn init() {
+    // SAFETY: a plain read of a flag set while the postmaster loads shared_preload_libraries.
+    if !unsafe { pg_sys::process_shared_preload_libraries_in_progress } {
+        ErrorReport::new(
+            PgSqlErrorCode::ERRCODE_OBJECT_NOT_IN_PREREQUISITE_STATE,
+            "the access security label provider is not available",
+            function_name!(),
+        )
+        .set_detail("access_pgrx was loaded on demand rather than from shared_preload_libraries, so not every query would be checked.")
+        .set_hint("Add access_pgrx to shared_preload_libraries and restart the server.")
+        .report(PgLogLevel::LOG);
+        return;
+    }
+    // SAFETY: called once per backend from _PG_init, before any query runs; the previous hooks are
+    // saved so they keep running after ours, and the callback lives as long as the library.
+    unsafe {
+        pg_sys::CacheRegisterRelcacheCallback(Some(relcache_invalidated), pg_sys::Datum::from(0));
+        pg_sys::register_label_provider(PROVIDER.as_ptr(), Some(check_relabel));
+        PREVIOUS_EXECUTOR_CHECK_PERMS = pg_sys::ExecutorCheckPerms_hook;
+        pg_sys::ExecutorCheckPerms_hook = Some(executor_check_perms);
+        PREVIOUS_OBJECT_ACCESS = pg_sys::object_access_hook;
+        pg_sys::object_access_hook = Some(object_access);
+    }
+}
+
+#[pg_guard]
+unsafe extern "C-unwind" fn relcache_invalidated(_arg: pg_sys::Datum, _relid: pg_sys::Oid) {
+    LABELLED.set(None);
+}
+
+/// Whether the current database has any `access` labels.
+fn any_labels() -> bool {
+    if let Some(labelled) = LABELLED.get() {
+        return labelled;
+    }
+    // The query below goes through the executor hook too; until it answers, assume there are
+    // labels, which only costs that query a lookup of its own.
+    LABELLED.set(Some(true));
+    let labelled = Spi::get_one_with_args::<bool>(
+        "SELECT EXISTS (SELECT FROM pg_catalog.pg_seclabel WHERE provider OPERATOR(pg_catalog.=) $1)",
+        &[PROVIDER.to_str().expect("the provider name is ASCII").into()],
+    )
+    .ok()
+    .flatten()
+    .unwrap_or(true);
+    LABELLED.set(Some(labelled));
+    labelled
+}
+
+/// Raise an error unless the extension is installed in the current database, since labels can't
+/// be checked without it.
+fn require_installed() {
+    if !catalog::installed() {
+        ErrorReport::new(
+            PgSqlErrorCode::ERRCODE_OBJECT_NOT_IN_PREREQUISITE_STATE,
+            "access security labels need the access_pgrx extension in this database",
+            function_name!(),
+        )
+        .set_hint("Run CREATE EXTENSION access_pgrx, or remove the labels with SECURITY LABEL FOR access ... IS NULL.")
+        .report(PgLogLevel::ERROR);
+    }
+}
+
+#[pg_guard]
+unsafe extern "C-unwind" fn check_relabel(
+    object: *const pg_sys::ObjectAddress,
+    label: *const c_char,
+) {
+    // SAFETY: SECURITY LABEL passes the object being labelled and the label, which is null when
+    // the label is being removed.
+    let object = unsafe { &*object };
+    if object.classId != pg_sys::RelationRelationId {
+        ErrorReport::new(
+            PgSqlErrorCode::ERRCODE_FEATURE_NOT_SUPPORTED,
+            "access security labels can only be set on tables and columns",
+            function_name!(),
+        )
+        .report(PgLogLevel::ERROR);
+    }
+    // SAFETY: the object is a relation; the invalidation is sent when the label is stored, and
+    // tells every backend to look for labels again.
+    unsafe { pg_sys::CacheInvalidateRelcacheByRelid(object.objectId) };
+    let Some(label) = (unsafe { label.as_ref() }) else {
+        return;
+    };
+    require_installed();
+    // SAFETY: a non-null label is a C string.
+    let text = unsafe { CStr::from_ptr(label) };
+    let text = syntax::utf8("accessexpression", text).unwrap_or_else(|e| syntax::raise(e));
+    if let Err(e) = ::access::expression(text) {
+        let located = syntax::parse_expression(text).err();
+        syntax::raise(syntax::invalid_input("accessexpression", text, located, e));
+    }
+}
+
+/// The access label on a table (`attnum` 0) or one of its columns, if it has one.
+fn label_of(relid: pg_sys::Oid, attnum: i32) -> Option<StoredExpression> {
+    let object = pg_sys::ObjectAddress {
+        classId: pg_sys::RelationRelationId,
+        objectId: relid,
+        objectSubId: attnum,
+    };
+    // SAFETY: GetSecurityLabel returns null or a palloc'd C string.
+    let label = unsafe { pg_sys::GetSecurityLabel(&object, PROVIDER.as_ptr()) };
+    if label.is_null() {
+        return None;
+    }
+    // SAFETY: as above; check_relabel only lets valid expressions be stored.
+    let text = unsafe { CStr::from_ptr(label) }
+        .to_string_lossy()
+        .into_owned();
+    let expression = AccessExpression(
+        ::access::expression(&text)
+            .unwrap_or_else(|_| panic!("stored security label \"{text}\" failed to parse")),
+    );
+    Some(StoredExpression::compile(expression.tree().as_ref()))
+}
+
+/// Whether the session's tokens satisfy the labels on `relid` and on the columns in `columns`
+/// (attribute numbers offset by `FirstLowInvalidHeapAttributeNumber`, as in permission bitmaps),
+/// raising a permission error if not and `report` is set.
+unsafe fn check_relation(
+    relid: pg_sys::Oid,
+    columns: &[*mut pg_sys::Bitmapset],
+    report: bool,
+) -> bool {
+    if !any_labels() {
+        return true;
+    }
+    let mut attnums: Vec<i32> = vec![0];
+    for &set in columns {
+        let mut member = -1;
+        loop {
+            // SAFETY: set is null or a valid Bitmapset from the range table.
+            member = unsafe { pg_sys::bms_next_member(set, member) };
+            if member < 0 {
+                break;
+            }
+            match member + pg_sys::FirstLowInvalidHeapAttributeNumber {
+                // A whole-row reference uses every column.
+                0 => attnums.extend(1..=unsafe { column_count(relid) }),
+                attnum if attnum > 0 => attnums.push(attnum),
+                _ => {}
+            }
+        }
+    }
+    attnums.sort_unstable();
+    attnums.dedup();
+    for attnum in attnums {
+        let Some(label) = label_of(relid, attnum) else {
+            continue;
+        };
+        require_installed();
+        if guc::with_current_tokens(|tokens| label.evaluate(tokens)) {
+            continue;
+        }
+        if report {
+            // SAFETY: relid is a relation the query uses, so it exists.
+            let relation = unsafe { CStr::from_ptr(pg_sys::get_rel_name(relid)) }.to_string_lossy();
+            let message = match attnum {
+                0 => format!("permission denied for table {relation}"),
+                _ => {
+                    // SAFETY: as above, and attnum is one of its columns.
+                    let column =
+                        unsafe { CStr::from_ptr(pg_sys::get_attname(relid, attnum as i16, false)) }
+                            .to_string_lossy()
+                            .into_owned();
+                    format!("permission denied for column {column} of table {relation}")
+                }
+            };
+            let label = label
+                .tree()
+                .map_or_else(String::new, |tree| tree.to_string());
+            ErrorReport::new(
+                PgSqlErrorCode::ERRCODE_INSUFFICIENT_PRIVILEGE,
+                message,
+                function_name!(),
+            )
+            .set_detail(format!(
+                "The access label \"{label}\" is not satisfied by the session's access tokens."
+            ))
+            .report(PgLogLevel::ERROR);
+        }
+        return false;
+    }
+    true
+}
+
+/// The number of columns `relid` has, dropped ones included.
+unsafe fn column_count(relid: pg_sys::Oid) -> i32 {
+    // SAFETY: relid is a relation the query has locked; it is closed again before returning.
+    unsafe {
+        let relation = pg_sys::RelationIdGetRelation(relid);
+        if relation.is_null() {
+            return 0;
+        }
+        let count = (*(*relation).rd_att).natts;
+        pg_sys::RelationClose(relation);
+        count
+    }
+}
+
+#[cfg(any(feature = "pg16", feature = "pg17", feature = "pg18"))]
+#[pg_guard]
+unsafe extern "C-unwind" fn executor_check_perms(
+    range_table: *mut pg_sys::List,
+    permissions: *mut pg_sys::List,
+    report: bool,
+) -> bool {
+    // SAFETY: the executor passes the query's RTEPermissionInfo list.
+    unsafe {
+        if let Some(previous) = PREVIOUS_EXECUTOR_CHECK_PERMS {
+            if !previous(range_table, permissions, report) {
+                return false;
+            }
+        }
+        PgList::<pg_sys::RTEPermissionInfo>::from_pg(permissions)
+            .iter_ptr()
+            .all(|info| {
+                let info = &*info;
+                check_relation(
+                    info.relid,
+                    &[info.selectedCols, info.insertedCols, info.updatedCols],
+                    report,
+                )
+            })
+    }
+}
+
+#[cfg(not(any(feature = "pg16", feature = "pg17", feature = "pg18")))]
+#[pg_guard]
+unsafe extern "C-unwind" fn executor_check_perms(
+    range_table: *mut pg_sys::List,
+    report: bool,
+) -> bool {
+    // SAFETY: the executor passes the query's range table.
+    unsafe {
+        if let Some(previous) = PREVIOUS_EXECUTOR_CHECK_PERMS {
+            if !previous(range_table, report) {
+                return false;
+            }
+        }
+        PgList::<pg_sys::RangeTblEntry>::from_pg(range_table)
+            .iter_ptr()
+            .filter(|&entry| (*entry).rtekind == pg_sys::RTEKind::RTE_RELATION)
+            .all(|entry| {
+                let entry = &*entry;
+                check_relation(
+                    entry.relid,
+                    &[entry.selectedCols, entry.insertedCols, entry.updatedCols],
+                    report,
+                )
+            })
+    }
+}
+
+#[pg_guard]
+unsafe extern "C-unwind" fn object_access(
+    access: pg_sys::ObjectAccessType::Type,
+    class_id: pg_sys::Oid,
+    object_id: pg_sys::Oid,
+    sub_id: i32,
+    arg: *mut c_void,
+) {
+    // SAFETY: the arguments are passed through unchanged to the previous hook.
+    unsafe {
+        if let Some(previous) = PREVIOUS_OBJECT_ACCESS {
+            previous(access, class_id, object_id, sub_id, arg);
+        }
+    }
+    if access == pg_sys::ObjectAccessType::OAT_DROP
+        && class_id == pg_sys::RelationRelationId
+        && any_labels()
+    {
+        if let Some(label) = label_of(object_id, sub_id) {
+            require_installed();
+            if !guc::with_current_tokens(|tokens| label.evaluate(tokens)) {
+                ErrorReport::new(
+                    PgSqlErrorCode::ERRCODE_INSUFFICIENT_PRIVILEGE,
+                    "cannot drop an object whose access label the session's access tokens do not satisfy",
+                    function_name!(),
+                )
+                .report(PgLogLevel::ERROR);
+            }
+        }
+    }
+}
@@ -0,0 +1,207 @@
+/*
+  Copyright 2025 Will Murnane
+
+  Licensed under the Apache License, Version 2.0 (the "License");
+  you may not use this file except in compliance with the License.
+  You may obtain a copy of the License at
+
+      http://www.apache.org/licenses/LICENSE-2.0
+
+  Unless required by applicable law or agreed to in writing, software
+  distributed under the License is distributed on an "AS IS" BASIS,
+  WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+  See the License for the specific language governing permissions and
+  limitations under the License.
+*/
+
+//! Signed token assertions, for applications that share one database role between many users.
+//!
+//! An assertion is a JSON Web Token signed with HS256 (HMAC-SHA-256) under the key in
+//! `access.signing_key`, whose claims are:
+//!
+//! - `sub`: who the tokens are for, reported by `access_current_subject()`;
+//! - `tokens`: the tokens, as `accesstokens` text;
+//! - `exp`: when the assertion expires, in seconds since the Unix epoch;
+//! - `nonce`: any string. A backend refuses a nonce it has already accepted, but other backends
+//!   don't know about it, so this only stops an assertion being used twice on one connection; an
+//!   assertion captured in transit can be used on another until it expires, so keep `exp` short.
+//!
+//! `access_set_signed_tokens()` checks the signature and expiry, and then assumes the tokens for
+//! the current role exactly as `access_assume_tokens()` does. The key is only visible to, and
+//! only settable by, superusers. While a key is configured, `access.tokens` is ignored, so a
+//! session that gives up its assertion's tokens holds none rather than whatever it then sets.
+
+use crate::storage::StoredTokens;
+use crate::{authorization, catalog, AccessTokens};
+use base64::engine::general_purpose::URL_SAFE_NO_PAD;
+use base64::Engine;
+use hmac::{Hmac, Mac};
+use pgrx::pg_sys::panic::ErrorReport;
+use pgrx::prelude::*;
+use pgrx::{
+    function_name, GucContext, GucFlags, GucRegistry, GucSetting, PgLogLevel, PgSqlErrorCode,
+};
+use serde_json::Value;
+use sha2::Sha256;
+use std::cell::RefCell;
+use std::collections::HashMap;
+use std::ffi::CString;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+static SIGNING_KEY: GucSetting<Option<CString>> = GucSetting::<Option<CString>>::new(None);
+
+thread_local! {
+    /// Nonces this backend has accepted, with the expiry of the assertion that carried them. Not
+    /// cleared by `DISCARD ALL`.
+    static SEEN_NONCES: RefCell<HashMap<String, u64>> = RefCell::new(HashMap::new());
+}
+
+pub(crate) fn init() {
+    GucRegistry::define_string_guc(
+        c"access.signing_key",
+        c"Key that signed token assertions are checked against.",
+        c"The HS256 key for access_set_signed_tokens(). Only superusers can see or change it.",
+        &SIGNING_KEY,
+        GucContext::Suset,
+        GucFlags::SUPERUSER_ONLY | GucFlags::NO_SHOW_ALL | GucFlags::NOT_IN_SAMPLE,
+    );
+}
+
+/// Whether a key for signed assertions is configured.
+pub(crate) fn signing_configured() -> bool {
+    SIGNING_KEY.get().is_some_and(|key| !key.is_empty())
+}
+
+/// The verified contents of an assertion.
+struct Claims {
+    subject: String,
+    tokens: StoredTokens,
+    expires: u64,
+    nonce: String,
+}
+
+/// Why an assertion was refused.
+enum Refusal {
+    Malformed(&'static str),
+    BadSignature,
+    Expired,
+    Replayed,
+}
+
+impl Refusal {
+    fn raise(self) -> ! {
+        let (code, message) = match self {
+            Refusal::Malformed(why) => (
+                PgSqlErrorCode::ERRCODE_INVALID_PARAMETER_VALUE,
+                format!("malformed token assertion: {why}"),
+            ),
+            Refusal::BadSignature => (
+                PgSqlErrorCode::ERRCODE_INVALID_AUTHORIZATION_SPECIFICATION,
+                "token assertion signature does not match".to_string(),
+            ),
+            Refusal::Expired => (
+                PgSqlErrorCode::ERRCODE_INVALID_AUTHORIZATION_SPECIFICATION,
+                "token assertion has expired".to_string(),
+            ),
+            Refusal::Replayed => (
+                PgSqlErrorCode::ERRCODE_INVALID_AUTHORIZATION_SPECIFICATION,
+                "token assertion nonce has already been used".to_string(),
+            ),
+        };
+        ErrorReport::new(code, message, function_name!()).report(PgLogLevel::ERROR);
+        unreachable!("ERROR-level reports do not return")
+    }
+}
+
+fn decode_part(part: &str) -> Result<Value, Refusal> {
+    let json = URL_SAFE_NO_PAD
+        .decode(part)
+        .map_err(|_| Refusal::Malformed("a part is not base64url"))?;
+    serde_json::from_slice(&json).map_err(|_| Refusal::Malformed("a part is not JSON"))
+}
+
+/// Check `assertion`'s signature under `key` and its expiry against `now`.
+fn verify(assertion: &str, key: &[u8], now: u64) -> Result<Claims, Refusal> {
+    let mut parts = assertion.split('.');
+    let (Some(header), Some(payload), Some(signature), None) =
+        (parts.next(), parts.next(), parts.next(), parts.next())
+    else {
+        return Err(Refusal::Malformed("expected three dot-separated parts"));
+    };
+    if decode_part(header)?.get("alg").and_then(Value::as_str) != Some("HS256") {
+        return Err(Refusal::Malformed("the algorithm must be HS256"));
+    }
+    let signature = URL_SAFE_NO_PAD
+        .decode(signature)
+        .map_err(|_| Refusal::Malformed("the signature is not base64url"))?;
+    let mut mac = Hmac::<Sha256>::new_from_slice(key).expect("HMAC accepts keys of any length");
+    mac.update(header.as_bytes());
+    mac.update(b".");
+    mac.update(payload.as_bytes());
+    mac.verify_slice(&signature)
+        .map_err(|_| Refusal::BadSignature)?;
+
+    let claims = decode_part(payload)?;
+    let claim = |name| claims.get(name).and_then(Value::as_str);
+    let (Some(subject), Some(tokens), Some(nonce)) =
+        (claim("sub"), claim("tokens"), claim("nonce"))
+    else {
+        return Err(Refusal::Malformed(
+            "\"sub\", \"tokens\" and \"nonce\" must be strings",
+        ));
+    };
+    let Some(expires) = claims.get("exp").and_then(Value::as_u64) else {
+        return Err(Refusal::Malformed("\"exp\" must be a number of seconds"));
+    };
+    if expires <= now {
+        return Err(Refusal::Expired);
+    }
+    let tokens = ::access::tokens(tokens)
+        .map_err(|_| Refusal::Malformed("\"tokens\" is not a valid accesstokens"))?;
+    Ok(Claims {
+        subject: subject.to_string(),
+        tokens: StoredTokens::from(&AccessTokens(tokens)),
+        expires,
+        nonce: nonce.to_string(),
+    })
+}
+
+/// Assume the tokens in a signed assertion for the rest of the session, if its signature and
+/// expiry check out. Returns the tokens assumed.
+#[pg_extern]
+fn access_set_signed_tokens(assertion: &str) -> StoredTokens {
+    let Some(key) = SIGNING_KEY.get().filter(|key| !key.is_empty()) else {
+        ErrorReport::new(
+            PgSqlErrorCode::ERRCODE_OBJECT_NOT_IN_PREREQUISITE_STATE,
+            "no key is configured for signed token assertions",
+            function_name!(),
+        )
+        .set_hint("A superuser can set one in access.signing_key.")
+        .report(PgLogLevel::ERROR);
+        unreachable!("ERROR-level reports do not return")
+    };
+    let now = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map_or(0, |since| since.as_secs());
+    let claims = verify(assertion, key.as_bytes(), now).unwrap_or_else(|refusal| refusal.raise());
+    SEEN_NONCES.with_borrow_mut(|seen| {
+        seen.retain(|_, expires| *expires > now);
+        if seen.contains_key(&claims.nonce) {
+            Refusal::Replayed.raise();
+        }
+        seen.insert(claims.nonce, claims.expires);
+    });
+    authorization::assume(
+        catalog::current_role(),
+        claims.tokens.clone(),
+        Some(claims.subject),
+    );
+    claims.tokens
+}
+
+/// The subject of the signed assertion the current tokens came from, or NULL if they didn't come
+/// from one.
+#[pg_extern(stable, parallel_restricted)]
+fn access_current_subject() -> Option<String> {
+    authorization::assumed_subject()
+}
@@ -0,0 +1,446 @@
+/*
+  Copyright 2025 Will Murnane
+
+  Licensed under the Apache License, Version 2.0 (the "License");
+  you may not use this file except in compliance with the License.
+  You may obtain a copy of the License at
+
+      http://www.apache.org/licenses/LICENSE-2.0
+
+  Unless required by applicable law or agreed to in writing, software
+  distributed under the License is distributed on an "AS IS" BASIS,
+  WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+  See the License for the specific language governing permissions and
+  limitations under the License.
+*/
+
+//! On-disk representation of `accessexpression` and `accesstokens`.
+//!
+//! Every stored value is a varlena whose payload starts with a two byte header: [`MAGIC`], then
+//! [`STORAGE_VERSION`]. `0xFF` can never begin a CBOR data item, which is how values written by
+//! earlier releases (pgrx's serde/CBOR encoding of the `::access` types) are told apart; those are
+//! still read, and are rewritten in the current format whenever they are stored again (see
+//! `access_reencode`). All integers after the header are unsigned LEB128 varints.
+//!
+//! An `accesstokens` payload is a token count, then each token as a byte length and its unescaped
+//! UTF-8 bytes, in canonical order.
+//!
+//! An `accessexpression` payload is an interned token table followed by a postfix program:
+//!
+//! - a token count, then each distinct token (length and bytes), in order of first use;
+//! - an instruction count, then the instructions. Each is a single varint `operand << 2 | opcode`:
+//!   opcode `0` pushes whether table entry `operand` is held, `1` pops `operand` values and pushes
+//!   whether all were true, and `2` pops `operand` values and pushes whether any was true.
+//!
+//! The empty expression has an empty program, which is always satisfied. Evaluating a label only
+//! needs the table and the program, so [`StoredExpression`] can be checked against
+//! [`StoredTokens`] without rebuilding (and re-canonicalizing) the `::access` values.
+
+use crate::syntax::{self, Expr, InputError};
+use crate::{AccessExpression, AccessTokens};
+use pgrx::datum::cbor_decode;
+use pgrx::prelude::*;
+use pgrx::AnyElement;
+use std::collections::{HashMap, HashSet};
+
+/// First byte of every value in the current storage format.
+pub const MAGIC: u8 = 0xFF;
+/// The storage format version written by this release.
+pub const STORAGE_VERSION: u8 = 1;
+
+/// Conversion between a Rust value and the payload of its varlena datum.
+pub(crate) trait Stored: Sized {
+    const TYPE_NAME: &'static str;
+
+    fn encode(&self) -> Vec<u8>;
+
+    fn decode(payload: &[u8]) -> Result<Self, String>;
+
+    /// Read a value written by a release that stored the pgrx CBOR encoding.
+    ///
+    /// # Safety
+    /// `datum` must be the (possibly toasted) varlena the payload came from.
+    unsafe fn decode_legacy(datum: pg_sys::Datum) -> Self;
+
+    /// Read a stored datum in either format.
+    ///
+    /// # Safety
+    /// `datum` must be a non-null varlena of this type.
+    unsafe fn from_stored(datum: pg_sys::Datum) -> Self {
+        // SAFETY: the caller guarantees datum is a varlena, which has bytea's layout.
+        let payload = unsafe {
+            <&[u8]>::from_polymorphic_datum(datum, false, pg_sys::BYTEAOID)
+                .expect("datum is not null")
+        };
+        match payload {
+            [MAGIC, STORAGE_VERSION, body @ ..] => Self::decode(body).unwrap_or_else(|detail| {
+                syntax::raise(InputError {
+                    code: PgSqlErrorCode::ERRCODE_DATA_CORRUPTED,
+                    message: format!("corrupt {} value", Self::TYPE_NAME),
+                    detail,
+                })
+            }),
+            [MAGIC, version, ..] => syntax::raise(InputError {
+                code: PgSqlErrorCode::ERRCODE_FEATURE_NOT_SUPPORTED,
+                message: format!(
+                    "{} value has unsupported storage version {version}",
+                    Self::TYPE_NAME
+                ),
+                detail: format!("This release reads storage version {STORAGE_VERSION}."),
+            }),
+            // SAFETY: not our format, so it is the CBOR written by earlier releases.
+            _ => unsafe { Self::decode_legacy(datum) },
+        }
+    }
+}
+
+/// An `accessexpression` as stored: its interned tokens and postfix program.
+#[derive(PartialEq, Eq, PartialOrd, Ord)]
+pub struct StoredExpression {
+    tokens: Vec<String>,
+    ops: Vec<Op>,
+}
+
+/// An `accesstokens` as stored: its unescaped tokens, in canonical order.
+#[derive(Clone, Default, PartialEq, Eq, PartialOrd, Ord)]
+pub struct StoredTokens {
+    tokens: Vec<String>,
+}
+
+#[derive(Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+enum Op {
+    Token(u32),
+    And(u32),
+    Or(u32),
+}
+
+impl StoredExpression {
+    /// Compile a tree (`None` being the empty expression) into a program.
+    pub(crate) fn compile(tree: Option<&Expr>) -> Self {
+        let mut program = StoredExpression {
+            tokens: Vec::new(),
+            ops: Vec::new(),
+        };
+        if let Some(tree) = tree {
+            program.emit(tree, &mut HashMap::new());
+        }
+        program
+    }
+
+    fn emit(&mut self, expr: &Expr, interned: &mut HashMap<String, u32>) {
+        let (children, op): (_, fn(u32) -> Op) = match expr {
+            Expr::Token(token) => {
+                let index = *interned.entry(token.clone()).or_insert_with(|| {
+                    self.tokens.push(token.clone());
+                    (self.tokens.len() - 1) as u32
+                });
+                self.ops.push(Op::Token(index));
+                return;
+            }
+            Expr::And(children) => (children, Op::And),
+            Expr::Or(children) => (children, Op::Or),
+        };
+        for child in children {
+            self.emit(child, interned);
+        }
+        self.ops.push(op(children.len() as u32));
+    }
+
+    /// The distinct tokens the expression mentions, in order of first use.
+    pub(crate) fn tokens(&self) -> &[String] {
+        &self.tokens
+    }
+
+    /// The number of instructions in the program, a rough measure of how costly it is to evaluate.
+    pub(crate) fn instructions(&self) -> usize {
+        self.ops.len()
+    }
+
+    /// Rebuild the tree the program was compiled from.
+    pub(crate) fn tree(&self) -> Option<Expr> {
+        let mut stack: Vec<Expr> = Vec::new();
+        for op in &self.ops {
+            let (n, junction): (u32, fn(Vec<Expr>) -> Expr) = match *op {
+                Op::Token(index) => {
+                    stack.push(Expr::Token(self.tokens[index as usize].clone()));
+                    continue;
+                }
+                Op::And(n) => (n, Expr::And),
+                Op::Or(n) => (n, Expr::Or),
+            };
+            let children = stack.split_off(stack.len() - n as usize);
+            stack.push(junction(children));
+        }
+        stack.pop()
+    }
+
+    /// Whether `held` satisfies this expression.
+    pub(crate) fn evaluate(&self, held: &StoredTokens) -> bool {
+        let held: HashSet<&str> = held.tokens.iter().map(String::as_str).collect();
+        let present: Vec<bool> = self
+            .tokens
+            .iter()
+            .map(|token| held.contains(token.as_str()))
+            .collect();
+        let mut stack: Vec<bool> = Vec::with_capacity(self.ops.len());
+        for op in &self.ops {
+            match *op {
+                Op::Token(index) => stack.push(present[index as usize]),
+                Op::And(n) => {
+                    let start = stack.len() - n as usize;
+                    let all = stack[start..].iter().all(|&b| b);
+                    stack.truncate(start);
+                    stack.push(all);
+                }
+                Op::Or(n) => {
+                    let start = stack.len() - n as usize;
+                    let any = stack[start..].iter().any(|&b| b);
+                    stack.truncate(start);
+                    stack.push(any);
+                }
+            }
+        }
+        stack.pop().unwrap_or(true)
+    }
+}
+
+impl From<&AccessTokens> for StoredTokens {
+    fn from(tokens: &AccessTokens) -> Self {
+        StoredTokens {
+            tokens: tokens.values(),
+        }
+    }
+}
+
+impl StoredTokens {
+    /// The unescaped tokens, in canonical order.
+    pub(crate) fn tokens(&self) -> &[String] {
+        &self.tokens
+    }
+
+    /// Whether `token` is one of these tokens.
+    pub(crate) fn contains(&self, token: &str) -> bool {
+        self.tokens.iter().any(|held| held == token)
+    }
+
+    /// The tokens for which `keep` holds; a subset of a canonical set is still canonical.
+    pub(crate) fn filter(&self, keep: impl Fn(&str) -> bool) -> StoredTokens {
+        StoredTokens {
+            tokens: self
+                .tokens
+                .iter()
+                .filter(|token| keep(token))
+                .cloned()
+                .collect(),
+        }
+    }
+}
+
+impl Stored for StoredExpression {
+    const TYPE_NAME: &'static str = "accessexpression";
+
+    fn encode(&self) -> Vec<u8> {
+        let mut out = vec![MAGIC, STORAGE_VERSION];
+        put_strings(&mut out, &self.tokens);
+        put_varint(&mut out, self.ops.len() as u32);
+        for op in &self.ops {
+            put_varint(
+                &mut out,
+                match *op {
+                    Op::Token(index) => index << 2,
+                    Op::And(n) => n << 2 | 1,
+                    Op::Or(n) => n << 2 | 2,
+                },
+            );
+        }
+        out
+    }
+
+    fn decode(payload: &[u8]) -> Result<Self, String> {
+        let mut reader = Reader(payload);
+        let tokens = reader.strings()?;
+        let count = reader.varint()?;
+        let mut ops = Vec::new();
+        let mut depth = 0usize;
+        for _ in 0..count {
+            let word = reader.varint()?;
+            let operand = word >> 2;
+            let op = match word & 3 {
+                0 if (operand as usize) < tokens.len() => Op::Token(operand),
+                0 => return Err(format!("instruction refers to missing token {operand}")),
+                1 => Op::And(operand),
+                2 => Op::Or(operand),
+                _ => return Err(format!("unknown opcode in instruction {word}")),
+            };
+            depth = match op {
+                Op::Token(_) => depth + 1,
+                Op::And(n) | Op::Or(n) if n >= 2 && n as usize <= depth => depth - n as usize + 1,
+                Op::And(n) | Op::Or(n) => {
+                    return Err(format!("junction of {n} operands with {depth} available"))
+                }
+            };
+            ops.push(op);
+        }
+        if depth > 1 || (depth == 0 && !ops.is_empty()) {
+            return Err(format!("program leaves {depth} values on the stack"));
+        }
+        reader.finish()?;
+        Ok(StoredExpression { tokens, ops })
+    }
+
+    unsafe fn decode_legacy(datum: pg_sys::Datum) -> Self {
+        // SAFETY: the caller guarantees datum is a legacy CBOR varlena.
+        let expression: ::access::AccessExpression = unsafe { cbor_decode(datum.cast_mut_ptr()) };
+        StoredExpression::compile(AccessExpression(expression).tree().as_ref())
+    }
+}
+
+impl Stored for AccessExpression {
+    const TYPE_NAME: &'static str = "accessexpression";
+
+    fn encode(&self) -> Vec<u8> {
+        StoredExpression::compile(self.tree().as_ref()).encode()
+    }
+
+    fn decode(payload: &[u8]) -> Result<Self, String> {
+        let program = StoredExpression::decode(payload)?;
+        AccessExpression::from_tree(program.tree().as_ref()).map_err(|e| format!("{e:?}"))
+    }
+
+    unsafe fn decode_legacy(datum: pg_sys::Datum) -> Self {
+        // SAFETY: the caller guarantees datum is a legacy CBOR varlena.
+        AccessExpression(unsafe { cbor_decode(datum.cast_mut_ptr()) })
+    }
+}
+
+impl Stored for StoredTokens {
+    const TYPE_NAME: &'static str = "accesstokens";
+
+    fn encode(&self) -> Vec<u8> {
+        let mut out = vec![MAGIC, STORAGE_VERSION];
+        put_strings(&mut out, &self.tokens);
+        out
+    }
+
+    fn decode(payload: &[u8]) -> Result<Self, String> {
+        let mut reader = Reader(payload);
+        let tokens = reader.strings()?;
+        reader.finish()?;
+        Ok(StoredTokens { tokens })
+    }
+
+    unsafe fn decode_legacy(datum: pg_sys::Datum) -> Self {
+        // SAFETY: the caller guarantees datum is a legacy CBOR varlena.
+        let tokens: ::access::AccessTokens = unsafe { cbor_decode(datum.cast_mut_ptr()) };
+        StoredTokens::from(&AccessTokens(tokens))
+    }
+}
+
+impl Stored for AccessTokens {
+    const TYPE_NAME: &'static str = "accesstokens";
+
+    fn encode(&self) -> Vec<u8> {
+        StoredTokens::from(self).encode()
+    }
+
+    fn decode(payload: &[u8]) -> Result<Self, String> {
+        let stored = StoredTokens::decode(payload)?;
+        AccessTokens::from_values(&stored.tokens).map_err(|e| format!("{e:?}"))
+    }
+
+    unsafe fn decode_legacy(datum: pg_sys::Datum) -> Self {
+        // SAFETY: the caller guarantees datum is a legacy CBOR varlena.
+        AccessTokens(unsafe { cbor_decode(datum.cast_mut_ptr()) })
+    }
+}
+
+/// The storage format of a stored `accessexpression` or `accesstokens` value: 0 for the CBOR
+/// encoding written by earlier releases, otherwise the storage version.
+#[pg_extern(immutable, parallel_safe, strict)]
+fn access_storage_version(value: AnyElement) -> i32 {
+    let oid = value.oid();
+    if oid != AccessExpression::type_oid() && oid != AccessTokens::type_oid() {
+        error!("access_storage_version() only accepts accessexpression or accesstokens values");
+    }
+    // SAFETY: both types are varlenas, which have bytea's layout.
+    let payload = unsafe {
+        <&[u8]>::from_polymorphic_datum(value.datum(), false, pg_sys::BYTEAOID)
+            .expect("strict function")
+    };
+    match payload {
+        [MAGIC, version, ..] => *version as i32,
+        _ => 0,
+    }
+}
+
+/// Return `expression` unchanged. Storing the result rewrites it in the current storage format:
+/// `UPDATE t SET label = access_reencode(label) WHERE access_storage_version(label) = 0;`
+#[pg_extern(immutable, parallel_safe, strict, name = "access_reencode")]
+fn access_reencode_expression(expression: AccessExpression) -> AccessExpression {
+    expression
+}
+
+/// Return `tokens` unchanged, for rewriting in the current storage format.
+#[pg_extern(immutable, parallel_safe, strict, name = "access_reencode")]
+fn access_reencode_tokens(tokens: AccessTokens) -> AccessTokens {
+    tokens
+}
+
+fn put_varint(out: &mut Vec<u8>, mut n: u32) {
+    while n >= 0x80 {
+        out.push((n as u8) | 0x80);
+        n >>= 7;
+    }
+    out.push(n as u8);
+}
+
+fn put_strings(out: &mut Vec<u8>, strings: &[String]) {
+    put_varint(out, strings.len() as u32);
+    for s in strings {
+        put_varint(out, s.len() as u32);
+        out.extend_from_slice(s.as_bytes());
+    }
+}
+
+struct Reader<'a>(&'a [u8]);
+
+impl Reader<'_> {
+    fn varint(&mut self) -> Result<u32, String> {
+        let mut value = 0u32;
+        for shift in (0..35).step_by(7) {
+            let (&byte, rest) = self.0.split_first().ok_or("truncated varint")?;
+            self.0 = rest;
+            value |= u32::from(byte & 0x7f)
+                .checked_shl(shift)
+                .filter(|_| shift < 28 || byte < 0x10)
+                .ok_or("varint overflows 32 bits")?;
+            if byte < 0x80 {
+                return Ok(value);
+            }
+        }
+        Err("varint overflows 32 bits".into())
+    }
+
+    fn strings(&mut self) -> Result<Vec<String>, String> {
+        let count = self.varint()?;
+        let mut strings = Vec::new();
+        for _ in 0..count {
+            let len = self.varint()? as usize;
+            if len > self.0.len() {
+                return Err(format!("string of {len} bytes overruns the value"));
+            }
+            let (bytes, rest) = self.0.split_at(len);
+            self.0 = rest;
+            strings.push(String::from_utf8(bytes.to_vec()).map_err(|e| e.to_string())?);
+        }
+        Ok(strings)
+    }
+
+    fn finish(&self) -> Result<(), String> {
+        if self.0.is_empty() {
+            Ok(())
+        } else {
+            Err(format!("{} trailing bytes", self.0.len()))
+        }
+    }
+}
@@ -0,0 +1,322 @@
+/*
+  Copyright 2025 Will Murnane
+
+  Licensed under the Apache License, Version 2.0 (the "License");
+  you may not use this file except in compliance with the License.
+  You may obtain a copy of the License at
+
+      http://www.apache.org/licenses/LICENSE-2.0
+
+  Unless required by applicable law or agreed to in writing, software
+  distributed under the License is distributed on an "AS IS" BASIS,
+  WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+  See the License for the specific language governing permissions and
+  limitations under the License.
+*/
+
+//! Position-aware parsing of access expression and access token text.
+//!
+//! `::access` decides whether a label is valid and what its canonical form is. This module walks
+//! the same grammar (see the accumulo-access specification) for two other reasons: to find out
+//! *where* rejected text stops making sense, so the error handed back to PostgreSQL can point a
+//! caret at the offending character; and to turn canonical text into an [`Expr`] tree for the
+//! parts of the extension that need to look inside a label.
+
+use pgrx::pg_sys::panic::ErrorReport;
+use pgrx::{function_name, PgLogLevel, PgSqlErrorCode};
+use std::ffi::CStr;
+use std::fmt::{self, Debug, Display, Formatter};
+
+/// Where, and why, a piece of label text failed to parse. `offset` counts characters, not bytes.
+pub(crate) struct SyntaxError {
+    pub offset: usize,
+    pub message: String,
+}
+
+/// An access expression as a tree. Tokens hold their unescaped value.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub(crate) enum Expr {
+    Token(String),
+    And(Vec<Expr>),
+    Or(Vec<Expr>),
+}
+
+impl Display for Expr {
+    /// Writes the expression in the specification's syntax, parenthesizing nested junctions.
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        let (children, junction) = match self {
+            Expr::Token(token) => return f.write_str(&quote(token)),
+            Expr::And(children) => (children, "&"),
+            Expr::Or(children) => (children, "|"),
+        };
+        for (i, child) in children.iter().enumerate() {
+            if i > 0 {
+                f.write_str(junction)?;
+            }
+            match child {
+                Expr::Token(_) => write!(f, "{child}")?,
+                _ => write!(f, "({child})")?,
+            }
+        }
+        Ok(())
+    }
+}
+
+/// Parse `text` as an access expression. The empty expression parses to `None`.
+pub(crate) fn parse_expression(text: &str) -> Result<Option<Expr>, SyntaxError> {
+    let mut scanner = Scanner::new(text);
+    if scanner.at_end() {
+        return Ok(None);
+    }
+    let expr = scanner.expression()?;
+    match scanner.peek() {
+        None => Ok(Some(expr)),
+        Some(')') => Err(scanner.error("unbalanced parentheses: \")\" has no matching \"(\"")),
+        Some(c) => Err(scanner.error(format!("unexpected {}", describe(c)))),
+    }
+}
+
+/// Parse `text` as a comma-separated list of access tokens, returning their unescaped values in
+/// the order written.
+pub(crate) fn parse_tokens(text: &str) -> Result<Vec<String>, SyntaxError> {
+    let mut scanner = Scanner::new(text);
+    let mut tokens = Vec::new();
+    if scanner.at_end() {
+        return Ok(tokens);
+    }
+    loop {
+        tokens.push(scanner.token()?);
+        match scanner.peek() {
+            None => return Ok(tokens),
+            Some(',') => scanner.pos += 1,
+            Some(c) => {
+                return Err(scanner.error(format!(
+                    "unexpected {} after token, expected \",\"",
+                    describe(c)
+                )))
+            }
+        }
+    }
+}
+
+/// Write `token` the way the specification requires: bare if it only uses the unquoted
+/// characters, otherwise in double quotes with `"` and `\` escaped.
+pub(crate) fn quote(token: &str) -> String {
+    if !token.is_empty() && token.chars().all(is_unquoted) {
+        return token.to_string();
+    }
+    let mut quoted = String::with_capacity(token.len() + 2);
+    quoted.push('"');
+    for c in token.chars() {
+        if matches!(c, '"' | '\\') {
+            quoted.push('\\');
+        }
+        quoted.push(c);
+    }
+    quoted.push('"');
+    quoted
+}
+
+/// A value that could not be read, either raised directly or saved into the caller's
+/// `ErrorSaveContext`.
+pub(crate) struct InputError {
+    pub code: PgSqlErrorCode,
+    pub message: String,
+    pub detail: String,
+}
+
+/// Interpret the raw `cstring` handed to an input function as UTF-8.
+pub(crate) fn utf8<'a>(type_name: &str, input: &'a CStr) -> Result<&'a str, InputError> {
+    input.to_str().map_err(|e| InputError {
+        code: PgSqlErrorCode::ERRCODE_INVALID_TEXT_REPRESENTATION,
+        message: format!("invalid input syntax for type {type_name}: input is not valid UTF-8"),
+        detail: format!(
+            "Invalid byte sequence after byte offset {}.",
+            e.valid_up_to()
+        ),
+    })
+}
+
+/// Build the error for label text that `::access` rejected. `located` is the error, if any, from
+/// running the matching `parse_*` function over the same text; if that can't pinpoint the
+/// problem, the error from `::access` is reported as-is.
+pub(crate) fn invalid_input<E: Debug>(
+    type_name: &str,
+    text: &str,
+    located: Option<SyntaxError>,
+    cause: E,
+) -> InputError {
+    match located {
+        Some(SyntaxError { offset, message }) => InputError {
+            code: PgSqlErrorCode::ERRCODE_INVALID_TEXT_REPRESENTATION,
+            message: format!("invalid input syntax for type {type_name}: {message}"),
+            detail: format!(
+                "Error at character {}:\n{text}\n{}^",
+                offset + 1,
+                " ".repeat(offset)
+            ),
+        },
+        None => InputError {
+            code: PgSqlErrorCode::ERRCODE_INVALID_TEXT_REPRESENTATION,
+            message: format!("invalid input syntax for type {type_name}: \"{text}\""),
+            detail: format!("{cause:?}"),
+        },
+    }
+}
+
+/// Raise `error` as an `ERROR`.
+pub(crate) fn raise(error: InputError) -> ! {
+    ErrorReport::new(error.code, error.message, function_name!())
+        .set_detail(error.detail)
+        .report(PgLogLevel::ERROR);
+    unreachable!("ERROR-level reports do not return")
+}
+
+fn describe(c: char) -> String {
+    if c.is_control() {
+        format!("character U+{:04X}", c as u32)
+    } else {
+        format!("\"{c}\"")
+    }
+}
+
+fn is_unquoted(c: char) -> bool {
+    c.is_ascii_alphanumeric() || matches!(c, '_' | '-' | '.' | ':' | '/')
+}
+
+struct Scanner {
+    chars: Vec<char>,
+    pos: usize,
+}
+
+impl Scanner {
+    fn new(text: &str) -> Self {
+        Scanner {
+            chars: text.chars().collect(),
+            pos: 0,
+        }
+    }
+
+    fn at_end(&self) -> bool {
+        self.pos >= self.chars.len()
+    }
+
+    fn peek(&self) -> Option<char> {
+        self.chars.get(self.pos).copied()
+    }
+
+    fn error(&self, message: impl Into<String>) -> SyntaxError {
+        self.error_at(self.pos, message)
+    }
+
+    fn error_at(&self, offset: usize, message: impl Into<String>) -> SyntaxError {
+        SyntaxError {
+            offset,
+            message: message.into(),
+        }
+    }
+
+    /// A run of terms joined by a single kind of junction.
+    fn expression(&mut self) -> Result<Expr, SyntaxError> {
+        let first = self.term()?;
+        let mut junction: Option<(char, usize)> = None;
+        let mut terms = vec![first];
+        while let Some(c @ ('&' | '|')) = self.peek() {
+            match junction {
+                Some((first, at)) if first != c => {
+                    return Err(self.error(format!(
+                        "cannot mix \"{first}\" (at character {}) and \"{c}\" without parentheses",
+                        at + 1
+                    )))
+                }
+                _ => junction = Some((c, self.pos)),
+            }
+            self.pos += 1;
+            terms.push(self.term()?);
+        }
+        Ok(match junction {
+            None => terms.pop().expect("at least one term was parsed"),
+            Some(('&', _)) => Expr::And(terms),
+            Some(_) => Expr::Or(terms),
+        })
+    }
+
+    /// A single token or a parenthesized expression.
+    fn term(&mut self) -> Result<Expr, SyntaxError> {
+        match self.peek() {
+            Some('(') => {
+                let open = self.pos;
+                self.pos += 1;
+                let inner = self.expression()?;
+                match self.peek() {
+                    Some(')') => {
+                        self.pos += 1;
+                        Ok(inner)
+                    }
+                    Some(c) => {
+                        Err(self.error(format!("unexpected {}, expected \")\"", describe(c))))
+                    }
+                    None => {
+                        Err(self.error_at(open, "unbalanced parentheses: \"(\" is never closed"))
+                    }
+                }
+            }
+            Some('&' | '|') | None => {
+                let found = self.peek().map_or("end of input".to_string(), describe);
+                Err(self.error(format!("unexpected {found}, expected a token or \"(\"")))
+            }
+            _ => self.token().map(Expr::Token),
+        }
+    }
+
+    fn token(&mut self) -> Result<String, SyntaxError> {
+        match self.peek() {
+            Some('"') => self.quoted(),
+            Some(c) if is_unquoted(c) => {
+                let start = self.pos;
+                while self.peek().is_some_and(is_unquoted) {
+                    self.pos += 1;
+                }
+                Ok(self.chars[start..self.pos].iter().collect())
+            }
+            Some(c) => Err(self.error(format!("unexpected {}, expected a token", describe(c)))),
+            None => Err(self.error("unexpected end of input, expected a token")),
+        }
+    }
+
+    fn quoted(&mut self) -> Result<String, SyntaxError> {
+        let open = self.pos;
+        let mut token = String::new();
+        self.pos += 1;
+        loop {
+            match self.peek() {
+                None => return Err(self.error_at(open, "unterminated quoted token")),
+                Some('"') if token.is_empty() => {
+                    return Err(self.error_at(open, "quoted tokens may not be empty"))
+                }
+                Some('"') => {
+                    self.pos += 1;
+                    return Ok(token);
+                }
+                Some('\\') => match self.chars.get(self.pos + 1) {
+                    Some(&escaped @ ('"' | '\\')) => {
+                        token.push(escaped);
+                        self.pos += 2;
+                    }
+                    _ => {
+                        return Err(self.error(
+                            "invalid escape in quoted token: only \\\" and \\\\ are allowed",
+                        ))
+                    }
+                },
+                Some(c) if c.is_ascii_control() => {
+                    return Err(self.error(format!("{} is not allowed in a token", describe(c))))
+                }
+                Some(c) => {
+                    token.push(c);
+                    self.pos += 1;
+                }
+            }
+        }
+    }
+}
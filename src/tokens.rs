@@ -0,0 +1,207 @@
+/*
+  Copyright 2025 Will Murnane
+
+  Licensed under the Apache License, Version 2.0 (the "License");
+  you may not use this file except in compliance with the License.
+  You may obtain a copy of the License at
+
+      http://www.apache.org/licenses/LICENSE-2.0
+
+  Unless required by applicable law or agreed to in writing, software
+  distributed under the License is distributed on an "AS IS" BASIS,
+  WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+  See the License for the specific language governing permissions and
+  limitations under the License.
+*/
+
+//! Set algebra on `accesstokens`, and conversions to and from ordinary SQL values.
+//!
+//! Everything here works on the unescaped token values, so quoting never has to be handled in
+//! SQL, and every result is rebuilt as a canonical `accesstokens`. Single tokens are passed as
+//! plain `text`: `access_tokens_add('A', 'needs quoting')` adds the token `"needs quoting"`, and
+//! `ARRAY['needs quoting']::accesstokens` is the set of just that token.
+//!
+//! `access_tokens_union_agg` and `access_tokens_intersect_agg` fold a column of token sets.
+
+use crate::storage::StoredTokens;
+use crate::{syntax, AccessTokens};
+use pgrx::pg_sys::panic::ErrorReport;
+use pgrx::prelude::*;
+use pgrx::{function_name, PgLogLevel, PgSqlErrorCode};
+use std::collections::HashSet;
+
+/// The canonical token set of `values`, raising an error if any of them can't be a token.
+/// Repeated values are kept once.
+pub(crate) fn canonical<S: AsRef<str>>(values: &[S]) -> StoredTokens {
+    let mut seen = HashSet::new();
+    let values: Vec<&str> = values
+        .iter()
+        .map(AsRef::as_ref)
+        .filter(|value| seen.insert(*value))
+        .collect();
+    if values.iter().any(|value| value.is_empty()) {
+        ErrorReport::new(
+            PgSqlErrorCode::ERRCODE_INVALID_PARAMETER_VALUE,
+            "access tokens may not be empty",
+            function_name!(),
+        )
+        .report(PgLogLevel::ERROR);
+    }
+    match AccessTokens::from_values(&values) {
+        Ok(tokens) => StoredTokens::from(&tokens),
+        Err(e) => {
+            let text = values
+                .iter()
+                .map(|value| syntax::quote(value))
+                .collect::<Vec<_>>()
+                .join(",");
+            let located = syntax::parse_tokens(&text).err();
+            syntax::raise(syntax::invalid_input("accesstokens", &text, located, e))
+        }
+    }
+}
+
+/// The tokens in either `a` or `b`.
+#[pg_operator(immutable, parallel_safe)]
+#[opname(||)]
+fn access_tokens_union(a: StoredTokens, b: StoredTokens) -> StoredTokens {
+    canonical(&[a.tokens(), b.tokens()].concat())
+}
+
+/// The tokens in both `a` and `b`.
+#[pg_extern(immutable, parallel_safe)]
+fn access_tokens_intersect(a: StoredTokens, b: StoredTokens) -> StoredTokens {
+    a.filter(|token| b.contains(token))
+}
+
+/// The tokens in `a` but not in `b`.
+#[pg_operator(immutable, parallel_safe)]
+#[opname(-)]
+fn access_tokens_difference(a: StoredTokens, b: StoredTokens) -> StoredTokens {
+    a.filter(|token| !b.contains(token))
+}
+
+/// Whether every token in `a` is also in `b`.
+#[pg_operator(immutable, parallel_safe)]
+#[opname(<@)]
+#[commutator(@>)]
+#[restrict(contsel)]
+#[join(contjoinsel)]
+fn access_tokens_subset(a: StoredTokens, b: StoredTokens) -> bool {
+    a.tokens().iter().all(|token| b.contains(token))
+}
+
+/// Whether every token in `b` is also in `a`.
+#[pg_operator(immutable, parallel_safe)]
+#[opname(@>)]
+#[commutator(<@)]
+#[restrict(contsel)]
+#[join(contjoinsel)]
+fn access_tokens_superset(a: StoredTokens, b: StoredTokens) -> bool {
+    b.tokens().iter().all(|token| a.contains(token))
+}
+
+/// Whether `a` and `b` have any token in common. Estimated like the array `&&`, which for a
+/// type without array statistics is a fixed selectivity.
+#[pg_operator(immutable, parallel_safe)]
+#[opname(&&)]
+#[commutator(&&)]
+#[restrict(arraycontsel)]
+#[join(arraycontjoinsel)]
+fn access_tokens_overlap(a: StoredTokens, b: StoredTokens) -> bool {
+    a.tokens().iter().any(|token| b.contains(token))
+}
+
+/// The number of tokens in `tokens`.
+#[pg_extern(immutable, parallel_safe)]
+fn access_tokens_cardinality(tokens: StoredTokens) -> i32 {
+    tokens.tokens().len() as i32
+}
+
+/// Whether `token` is one of `tokens`.
+#[pg_extern(immutable, parallel_safe)]
+fn access_tokens_contains(tokens: StoredTokens, token: &str) -> bool {
+    tokens.contains(token)
+}
+
+/// `tokens` with `token` added.
+#[pg_extern(immutable, parallel_safe)]
+fn access_tokens_add(tokens: StoredTokens, token: &str) -> StoredTokens {
+    if tokens.contains(token) {
+        return tokens;
+    }
+    let mut values = tokens.tokens().to_vec();
+    values.push(token.to_string());
+    canonical(&values)
+}
+
+/// `tokens` with `token` removed, if it was there.
+#[pg_extern(immutable, parallel_safe)]
+fn access_tokens_remove(tokens: StoredTokens, token: &str) -> StoredTokens {
+    tokens.filter(|held| held != token)
+}
+
+/// The tokens in `tokens`, unescaped, in canonical order.
+#[pg_extern(immutable, parallel_safe)]
+fn access_tokens_to_array(tokens: StoredTokens) -> Vec<String> {
+    tokens.tokens().to_vec()
+}
+
+/// The token set with each element of `values` as a token, taken as is rather than parsed.
+#[pg_extern(immutable, parallel_safe)]
+fn access_tokens_from_array(values: Vec<Option<String>>) -> StoredTokens {
+    let values: Vec<String> = values
+        .into_iter()
+        .map(|value| {
+            value.unwrap_or_else(|| {
+                ErrorReport::new(
+                    PgSqlErrorCode::ERRCODE_NULL_VALUE_NOT_ALLOWED,
+                    "access tokens may not be null",
+                    function_name!(),
+                )
+                .report(PgLogLevel::ERROR);
+                unreachable!("ERROR-level reports do not return")
+            })
+        })
+        .collect();
+    canonical(&values)
+}
+
+/// Each token in `tokens`, unescaped, in canonical order.
+#[pg_extern(immutable, parallel_safe)]
+fn access_tokens_unnest(tokens: StoredTokens) -> SetOfIterator<'static, String> {
+    SetOfIterator::new(tokens.tokens().to_vec())
+}
+
+extension_sql!(
+    r#"
+CREATE CAST (accesstokens AS text[]) WITH FUNCTION access_tokens_to_array(accesstokens);
+CREATE CAST (text[] AS accesstokens) WITH FUNCTION access_tokens_from_array(text[]);
+"#,
+    name = "accesstokens_array_casts",
+    requires = [access_tokens_to_array, access_tokens_from_array]
+);
+
+extension_sql!(
+    r#"
+CREATE AGGREGATE access_tokens_union_agg(accesstokens) (
+    SFUNC = access_tokens_union,
+    STYPE = accesstokens,
+    COMBINEFUNC = access_tokens_union,
+    PARALLEL = SAFE
+);
+COMMENT ON AGGREGATE access_tokens_union_agg(accesstokens) IS
+    'Every token in any input set';
+
+CREATE AGGREGATE access_tokens_intersect_agg(accesstokens) (
+    SFUNC = access_tokens_intersect,
+    STYPE = accesstokens,
+    COMBINEFUNC = access_tokens_intersect,
+    PARALLEL = SAFE
+);
+COMMENT ON AGGREGATE access_tokens_intersect_agg(accesstokens) IS
+    'The tokens in every input set';
+"#,
+    name = "access_tokens_aggregates",
+    requires = [access_tokens_union, access_tokens_intersect]
+);
@@ -0,0 +1,249 @@
+/*
+  Copyright 2025 Will Murnane
+
+  Licensed under the Apache License, Version 2.0 (the "License");
+  you may not use this file except in compliance with the License.
+  You may obtain a copy of the License at
+
+      http://www.apache.org/licenses/LICENSE-2.0
+
+  Unless required by applicable law or agreed to in writing, software
+  distributed under the License is distributed on an "AS IS" BASIS,
+  WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+  See the License for the specific language governing permissions and
+  limitations under the License.
+*/
+
+//! Binary send/receive for `accessexpression` and `accesstokens`.
+//!
+//! This is the format used by `COPY ... (FORMAT binary)` and by clients that ask for binary
+//! results. All integers are big-endian, as usual for the PostgreSQL wire protocol, and every
+//! value starts with a one-byte format version, currently [`WIRE_VERSION`].
+//!
+//! An `accessexpression` is the version byte followed by its canonical tree, or nothing else for
+//! the empty expression. Each node is a one-byte tag:
+//!
+//! - `0` (token): a `u32` byte length, then that many bytes of UTF-8 holding the unescaped token.
+//! - `1` (and) or `2` (or): a `u32` child count of at least two, then the children.
+//!
+//! An `accesstokens` is the version byte, a `u32` count, then each token as a `u32` byte length
+//! and its unescaped UTF-8 bytes, in canonical order.
+//!
+//! Senders always produce canonical values. Receivers accept any well-formed tree or token list
+//! and canonicalize it, exactly as the text input functions do.
+
+use crate::syntax::{self, Expr, InputError};
+use crate::{AccessExpression, AccessTokens};
+use pgrx::prelude::*;
+use pgrx::Internal;
+
+/// The wire format version written by the send functions and understood by the receive functions.
+pub const WIRE_VERSION: u8 = 1;
+
+const TAG_TOKEN: u8 = 0;
+const TAG_AND: u8 = 1;
+const TAG_OR: u8 = 2;
+
+#[pg_extern(immutable, parallel_safe, strict)]
+fn accessexpression_send(value: AccessExpression) -> Vec<u8> {
+    let mut out = vec![WIRE_VERSION];
+    if let Some(tree) = value.tree() {
+        encode_expr(&tree, &mut out);
+    }
+    out
+}
+
+#[pg_extern(immutable, parallel_safe, strict)]
+fn accessexpression_recv(mut buf: Internal) -> AccessExpression {
+    // SAFETY: PostgreSQL calls a type's receive function with a StringInfo holding the message.
+    let bytes = unsafe { remaining(&mut buf) };
+    decode_expression(bytes).unwrap_or_else(|e| syntax::raise(e))
+}
+
+#[pg_extern(immutable, parallel_safe, strict)]
+fn accesstokens_send(value: AccessTokens) -> Vec<u8> {
+    let tokens = value.values();
+    let mut out = vec![WIRE_VERSION];
+    put_u32(&mut out, tokens.len());
+    for token in &tokens {
+        put_str(&mut out, token);
+    }
+    out
+}
+
+#[pg_extern(immutable, parallel_safe, strict)]
+fn accesstokens_recv(mut buf: Internal) -> AccessTokens {
+    // SAFETY: PostgreSQL calls a type's receive function with a StringInfo holding the message.
+    let bytes = unsafe { remaining(&mut buf) };
+    decode_tokens(bytes).unwrap_or_else(|e| syntax::raise(e))
+}
+
+/// Consume everything left unread in the receive buffer.
+unsafe fn remaining(buf: &mut Internal) -> &[u8] {
+    let buf = unsafe { buf.get_mut::<pg_sys::StringInfoData>() }
+        .expect("receive functions are always passed a buffer");
+    let start = buf.cursor as usize;
+    let end = buf.len as usize;
+    buf.cursor = buf.len;
+    // SAFETY: data[cursor..len] is the unread part of the message, which lives as long as the call.
+    unsafe { std::slice::from_raw_parts(buf.data.add(start).cast::<u8>(), end - start) }
+}
+
+fn encode_expr(expr: &Expr, out: &mut Vec<u8>) {
+    let (tag, children) = match expr {
+        Expr::Token(token) => {
+            out.push(TAG_TOKEN);
+            put_str(out, token);
+            return;
+        }
+        Expr::And(children) => (TAG_AND, children),
+        Expr::Or(children) => (TAG_OR, children),
+    };
+    out.push(tag);
+    put_u32(out, children.len());
+    for child in children {
+        encode_expr(child, out);
+    }
+}
+
+fn put_u32(out: &mut Vec<u8>, n: usize) {
+    let n = u32::try_from(n).expect("label components are limited to 1GB");
+    out.extend_from_slice(&n.to_be_bytes());
+}
+
+fn put_str(out: &mut Vec<u8>, s: &str) {
+    put_u32(out, s.len());
+    out.extend_from_slice(s.as_bytes());
+}
+
+fn decode_expression(bytes: &[u8]) -> Result<AccessExpression, InputError> {
+    let mut reader = Reader::new("accessexpression", bytes)?;
+    let tree = if reader.at_end() {
+        None
+    } else {
+        Some(reader.expr()?)
+    };
+    reader.finish()?;
+    AccessExpression::from_tree(tree.as_ref())
+        .map_err(|e| reader.error(format!("value is not a valid access expression: {e:?}")))
+}
+
+fn decode_tokens(bytes: &[u8]) -> Result<AccessTokens, InputError> {
+    let mut reader = Reader::new("accesstokens", bytes)?;
+    let count = reader.u32()?;
+    let mut tokens = Vec::new();
+    for _ in 0..count {
+        tokens.push(reader.token()?);
+    }
+    reader.finish()?;
+    AccessTokens::from_values(&tokens)
+        .map_err(|e| reader.error(format!("value is not a valid token list: {e:?}")))
+}
+
+struct Reader<'a> {
+    type_name: &'static str,
+    bytes: &'a [u8],
+    pos: usize,
+}
+
+impl<'a> Reader<'a> {
+    fn new(type_name: &'static str, bytes: &'a [u8]) -> Result<Self, InputError> {
+        let mut reader = Reader {
+            type_name,
+            bytes,
+            pos: 0,
+        };
+        match reader.take(1)? {
+            [WIRE_VERSION] => Ok(reader),
+            [version] => Err(reader.error(format!("unsupported format version {version}"))),
+            _ => unreachable!("take(1) returns one byte"),
+        }
+    }
+
+    fn error(&self, detail: impl Into<String>) -> InputError {
+        InputError {
+            code: PgSqlErrorCode::ERRCODE_INVALID_BINARY_REPRESENTATION,
+            message: format!("invalid binary representation for type {}", self.type_name),
+            detail: detail.into(),
+        }
+    }
+
+    fn at_end(&self) -> bool {
+        self.pos >= self.bytes.len()
+    }
+
+    fn finish(&self) -> Result<(), InputError> {
+        if self.at_end() {
+            Ok(())
+        } else {
+            Err(self.error(format!(
+                "{} unexpected trailing bytes",
+                self.bytes.len() - self.pos
+            )))
+        }
+    }
+
+    fn take(&mut self, n: usize) -> Result<&'a [u8], InputError> {
+        let end = self
+            .pos
+            .checked_add(n)
+            .filter(|&end| end <= self.bytes.len());
+        match end {
+            Some(end) => {
+                let taken = &self.bytes[self.pos..end];
+                self.pos = end;
+                Ok(taken)
+            }
+            None => Err(self.error(format!(
+                "message ends after {} bytes, but {n} more were expected at offset {}",
+                self.bytes.len(),
+                self.pos
+            ))),
+        }
+    }
+
+    fn u32(&mut self) -> Result<u32, InputError> {
+        let bytes = self.take(4)?;
+        Ok(u32::from_be_bytes(
+            bytes.try_into().expect("took four bytes"),
+        ))
+    }
+
+    fn token(&mut self) -> Result<String, InputError> {
+        let len = self.u32()? as usize;
+        if len == 0 {
+            return Err(self.error(format!("empty token at offset {}", self.pos)));
+        }
+        let bytes = self.take(len)?;
+        String::from_utf8(bytes.to_vec())
+            .map_err(|e| self.error(format!("token is not valid UTF-8: {e}")))
+    }
+
+    fn expr(&mut self) -> Result<Expr, InputError> {
+        pgrx::check_for_interrupts!();
+        // Deeply nested input recurses here; let PostgreSQL stop it before the stack runs out.
+        unsafe { pg_sys::check_stack_depth() };
+        let tag = self.take(1)?[0];
+        if tag == TAG_TOKEN {
+            return self.token().map(Expr::Token);
+        }
+        if tag != TAG_AND && tag != TAG_OR {
+            return Err(self.error(format!("unknown node tag {tag} at offset {}", self.pos - 1)));
+        }
+        let count = self.u32()?;
+        if count < 2 {
+            return Err(self.error(format!(
+                "junction with {count} children at offset {}",
+                self.pos - 5
+            )));
+        }
+        let children = (0..count)
+            .map(|_| self.expr())
+            .collect::<Result<Vec<_>, _>>()?;
+        Ok(if tag == TAG_AND {
+            Expr::And(children)
+        } else {
+            Expr::Or(children)
+        })
+    }
+}
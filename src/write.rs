@@ -0,0 +1,142 @@
+/*
+  Copyright 2025 Will Murnane
+
+  Licensed under the Apache License, Version 2.0 (the "License");
+  you may not use this file except in compliance with the License.
+  You may obtain a copy of the License at
+
+      http://www.apache.org/licenses/LICENSE-2.0
+
+  Unless required by applicable law or agreed to in writing, software
+  distributed under the License is distributed on an "AS IS" BASIS,
+  WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+  See the License for the specific language governing permissions and
+  limitations under the License.
+*/
+
+//! Checks on the labels a session writes.
+//!
+//! Policies' `USING` clauses only filter what a session reads. These checks stop it writing a row
+//! it couldn't then read: the session's tokens (see `access_current_tokens()`) must satisfy the new
+//! label, and optionally, as in Accumulo, the label may only mention tokens the session holds.
+//! Violations are reported as `insufficient_privilege` (42501), the same as a failed `WITH CHECK`.
+//!
+//! The check is available both as a function for a policy's `WITH CHECK` clause and as a row
+//! trigger, for tables without row level security or writers that bypass it. The trigger also
+//! plays the part of the policy's `USING` clause for the rows being changed: an update or delete
+//! is refused unless the session's tokens satisfy the existing row's label.
+//!
+//! Both read the session's assumed tokens, which only the leader has, so neither runs in a
+//! parallel worker.
+
+use crate::guc;
+use crate::storage::StoredExpression;
+use pgrx::pg_sys::panic::ErrorReport;
+use pgrx::prelude::*;
+use pgrx::{function_name, PgLogLevel, PgSqlErrorCode, PgTrigger, PgTriggerError};
+
+/// The text of `label`, for error details.
+fn text(label: &StoredExpression) -> String {
+    label
+        .tree()
+        .map_or_else(String::new, |tree| tree.to_string())
+}
+
+/// Raise an error unless the current session may change or delete a row labelled `label`.
+fn check_existing(label: &StoredExpression) {
+    if !guc::with_current_tokens(|tokens| label.evaluate(tokens)) {
+        ErrorReport::new(
+            PgSqlErrorCode::ERRCODE_INSUFFICIENT_PRIVILEGE,
+            "existing row's access label is not satisfied by the session's access tokens",
+            function_name!(),
+        )
+        .set_detail(format!("The label is \"{}\".", text(label)))
+        .report(PgLogLevel::ERROR);
+    }
+}
+
+/// Raise an error unless the current session may write a row labelled `label`.
+fn check_write(label: &StoredExpression, require_held: bool) {
+    let text = || text(label);
+    let unheld: Vec<String> = guc::with_current_tokens(|tokens| {
+        if !label.evaluate(tokens) {
+            ErrorReport::new(
+                PgSqlErrorCode::ERRCODE_INSUFFICIENT_PRIVILEGE,
+                "new row's access label is not satisfied by the session's access tokens",
+                function_name!(),
+            )
+            .set_detail(format!("The label is \"{}\".", text()))
+            .report(PgLogLevel::ERROR);
+        }
+        label
+            .tokens()
+            .iter()
+            .filter(|token| require_held && !tokens.contains(token))
+            .cloned()
+            .collect()
+    });
+    if !unheld.is_empty() {
+        ErrorReport::new(
+            PgSqlErrorCode::ERRCODE_INSUFFICIENT_PRIVILEGE,
+            "new row's access label mentions access tokens the session does not hold",
+            function_name!(),
+        )
+        .set_detail(format!(
+            "The label is \"{}\"; tokens not held: {}.",
+            text(),
+            unheld.join(", ")
+        ))
+        .report(PgLogLevel::ERROR);
+    }
+}
+
+/// True if the current session may write a row labelled `label`; raises an error otherwise. With
+/// `require_held`, the label must also mention only tokens the session holds.
+#[pg_extern(stable, parallel_restricted)]
+fn access_check_write(label: StoredExpression, require_held: default!(bool, false)) -> bool {
+    check_write(&label, require_held);
+    true
+}
+
+/// Row trigger checking the label in the column named by its first argument, as
+/// `access_check_write` does; a second argument of `require_held` turns on that option. Updates
+/// and deletes also need the session to satisfy the existing row's label; an update's new label is
+/// not checked again if it is unchanged.
+#[pg_trigger]
+fn access_check_label<'a>(
+    trigger: &'a PgTrigger<'a>,
+) -> Result<Option<PgHeapTuple<'a, AllocatedByPostgres>>, PgTriggerError> {
+    let args = trigger.extra_args()?;
+    let (column, require_held) = match args.as_slice() {
+        [column] => (column, false),
+        [column, option] if option == "require_held" => (column, true),
+        _ => {
+            ErrorReport::new(
+                PgSqlErrorCode::ERRCODE_TRIGGERED_ACTION_EXCEPTION,
+                "access_check_label() expects a column name, optionally followed by 'require_held'",
+                function_name!(),
+            )
+            .report(PgLogLevel::ERROR);
+            unreachable!("ERROR-level reports do not return")
+        }
+    };
+    let (new, old) = (trigger.new(), trigger.old());
+    let label = |row: &PgHeapTuple<'a, AllocatedByPostgres>| {
+        row.get_by_name::<StoredExpression>(column)
+            .unwrap_or_else(|e| panic!("cannot read access label column \"{column}\": {e}"))
+    };
+    let label_old = old.as_ref().and_then(label);
+    if let Some(label_old) = &label_old {
+        check_existing(label_old);
+    }
+    if let Some(label_new) = new.as_ref().and_then(label) {
+        let unchanged = label_old
+            .as_ref()
+            .is_some_and(|label_old| label_old.tree() == label_new.tree());
+        if !unchanged {
+            check_write(&label_new, require_held);
+        }
+    }
+    // A BEFORE DELETE trigger has no new row, and must return the old one to let the delete go on.
+    Ok(new.or(old))
+}